@@ -39,7 +39,7 @@ run_plants () {
         file_in="../$in_dir/$f"
         file_out="../$out_tmp/$f"
         if [[ $(file --mime-type -b "$file_in") == text/* ]]; then
-            cargo run --release -- "$file_in" "$file_out" 1 2> /dev/null &
+            cargo run --release -- "$file_in" "$file_out" --save-each-step 2> /dev/null &
         fi
     done
     wait
@@ -57,7 +57,7 @@ run_graph () {
     for f in $ls_tmp; do
         file_in="../$out_tmp/$f"
         file_out="../$out_dir/$f"
-        cargo run --release -- "$file_in" "$file_out" $angle $dist 2> /dev/null &
+        cargo run --release -- "$file_in" "$file_out" ${angle:+--angle $angle} ${dist:+--step $dist} 2> /dev/null &
     done
     wait
     cd ..
@@ -0,0 +1,233 @@
+use std::process;
+use clap::Parser;
+use graph3d::cli::{Format, RenderArgs};
+use graph3d::engine::{self, Lod, Module, Params};
+use graph3d::gltf;
+use graph3d::mesh::{self, Mesh, Shading};
+use graph3d::object::Instances;
+use graph3d::topology::Topology;
+use graph3d::vector3::Vector3;
+use plants::{LSystem, SymbolString};
+
+//header of the JSON output of plants: tropism, objects and rendering parameters of the grammar
+fn header(lsystem: &LSystem) -> engine::Header {
+    let header = lsystem.json_header();
+    let render = header.render;
+    engine::Header{
+        tropism: header.tropism.map(|t| t.map(f64::from)),
+        objects: header.objects.into_iter().collect(),
+        render: Params{
+            angle: render.angle.map(f64::from),
+            dist: render.step.map(f64::from),
+            width: render.width.map(|w| (w[0] as f64, w[1] as f64)),
+            shrink: render.shrink.map(f64::from),
+            colors: render.colors
+        }
+    }
+}
+
+//input of a derived string, with the tropism and the object meshes of base.
+//Modules are the ones read_str gets from plants' output
+fn to_input(base: &engine::Input, s: &SymbolString) -> engine::Input {
+    let modules = s.symbols.iter()
+        .map(|sym| {
+            if sym.object {
+                Module{sym: String::from("~"), params: Vec::new(),
+                    object: Some(format!("{}{}", sym.rule_set, sym.sym))}
+            } else {
+                Module{sym: sym.sym.clone(),
+                    params: sym.get_vec().iter().map(|v| *v as f64).collect(),
+                    object: None}
+            }
+        })
+        .collect();
+
+    engine::Input{tropism: base.tropism, meshes: base.meshes.clone(), modules, params: Params::default()}
+}
+
+/// Derive a grammar and write the geometry, without intermediate file.
+/// Rendering parameters are read from the grammar directives, flags override them.
+#[derive(Parser)]
+#[command(name = "plants3d")]
+struct Args {
+    /// File containing the rules
+    rules: String,
+
+    /// Name of the output files, OUTPUT.obj and its material library OUTPUT.mtl, or OUTPUT.glb
+    output: String,
+
+    /// Number of derivation steps, overrides #niter
+    #[arg(short = 'n', long)]
+    iterations: Option<usize>,
+
+    /// Seed of stochastic rules, overrides #seed
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Number of threads used for rewriting
+    #[arg(short = 'j', long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    threads: u64,
+
+    /// Write each derivation step to OUTPUT_0001.obj, OUTPUT_0002.obj..., sharing OUTPUT.mtl
+    /// (OUTPUT_0001.glb... with --format glb)
+    #[arg(long)]
+    animate: bool,
+
+    /// Number of frames per derivation step, parameters declared with #growth are
+    /// interpolated between two steps
+    #[arg(long, default_value_t = 1, requires = "animate",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    frames_per_step: u64,
+
+    /// Scale the plant to fit in a cube of this size, with the same scale for all the frames
+    #[arg(long, value_name = "SIZE")]
+    fit: Option<f64>,
+
+    #[command(flatten)]
+    render: RenderArgs,
+}
+
+//meshes of a derived string, indexed with their color index, its objects
+//and the number of segments removed by merging
+fn render(base: &engine::Input, s: &SymbolString, params: &Params, lod: &Lod)
+          -> Result<(Vec<Mesh>, Instances, usize), String> {
+    let (segments, leaves, objects) = engine::interpret(to_input(base, s), params)?;
+    let (segments, removed) = engine::process_segments(segments);
+    Ok((engine::gen_meshes(segments, leaves, params.palette().len() as i64, lod), objects, removed))
+}
+
+//write a frame scaled by scale, OBJ files use the material library mtl
+fn write(format: Format, shading: Shading, (mut meshes, objects): (Vec<Mesh>, Instances),
+         palette: &[(f64, f64, f64)], scale: f64, path: &str, mtl: &str) -> Result<(), String> {
+    match format {
+        Format::Obj => {
+            engine::merge_objects(&mut meshes, &objects);
+            for m in &mut meshes {
+                m.scale(scale);
+            }
+            mesh::write_obj(&meshes, path, mtl, shading)?;
+        },
+        Format::Glb => gltf::write_glb(&meshes, &objects, palette, scale, shading, path)?
+    }
+    println!("Successfully wrote to {}", path);
+    Ok(())
+}
+
+//call f with the number and the string of each frame, in order, and return the last
+//derived string. Without --animate, the only frame is the last derivation step.
+//Stops at the first error of f
+fn for_each_frame(lsystem: &LSystem, args: &Args, mut f: impl FnMut(usize, &SymbolString) -> Result<(), String>)
+                  -> Result<SymbolString, String> {
+    let n_iter = args.iterations.unwrap_or(lsystem.n_iter());
+    if !args.animate {
+        let res = lsystem.derive_last(n_iter);
+        f(0, &res)?;
+        return Ok(res);
+    }
+
+    let n_frames = args.frames_per_step;
+    let mut i = 0;
+    let mut s = lsystem.axiom().clone();
+    for step in 0..n_iter {
+        let next = lsystem.step(&s, step);
+        for k in 1..n_frames {
+            f(i, &lsystem.grow_step(&s, step, k as f32 / n_frames as f32))?;
+            i += 1;
+        }
+        f(i, &next)?;
+        i += 1;
+        s = next;
+    }
+    Ok(s)
+}
+
+//scale factor fitting all the bounding boxes in a cube of the given size
+fn fit_scale(boxes: impl Iterator<Item = (Vector3, Vector3)>, size: f64) -> f64 {
+    let boxes: Vec<_> = boxes.collect();
+    let extent = (0..3)
+        .map(|k| {
+            let min = boxes.iter().map(|b| b.0.to_arr()[k]).fold(f64::INFINITY, f64::min);
+            let max = boxes.iter().map(|b| b.1.to_arr()[k]).fold(f64::NEG_INFINITY, f64::max);
+            max - min
+        })
+        .fold(0.0, f64::max);
+
+    if extent > 0.0 {size / extent} else {1.0}
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut lsystem = match LSystem::load(&args.rules, args.seed) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    for w in &lsystem.warnings {
+        eprintln!("warning: {}", w);
+    }
+    lsystem.set_threads(args.threads as usize);
+    println!("Seed: {}", lsystem.seed);
+
+    if let Err(e) = run(&args, &lsystem) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &Args, lsystem: &LSystem) -> Result<(), String> {
+    //object meshes and rendering parameters are read once for all the frames
+    let mut base = engine::Input::new(header(lsystem), Vec::new())?;
+    let params = args.render.params()?.or(std::mem::take(&mut base.params));
+
+    let lod = args.render.lod(params.widths());
+
+    //the whole sequence is rendered a first time to get the scale, only the boxes are kept
+    let scale = match args.fit {
+        Some(size) => {
+            let mut boxes = Vec::new();
+            for_each_frame(lsystem, args, |_, s| {
+                let (meshes, objects, _) = render(&base, s, &params, &lod)?;
+                boxes.extend(meshes.iter().filter_map(|m| m.bounding_box()));
+                boxes.extend(objects.bounding_boxes());
+                Ok(())
+            })?;
+            fit_scale(boxes.into_iter(), size)
+        },
+        None => 1.0
+    };
+
+    //all the frames share the same material library
+    let format = args.render.format;
+    let output = args.output.trim_end_matches(&format!(".{}", format.extension()));
+    let mtl = format!("{}.mtl", output);
+    let palette = params.palette();
+    if format == Format::Obj {
+        mesh::write_mtl(&palette, &mtl)?;
+        println!("Successfully wrote to {}", mtl);
+    }
+
+    //frames are written as soon as they are rendered
+    let mut removed = 0;
+    let res = for_each_frame(lsystem, args, |i, s| {
+        let (meshes, objects, frame_removed) = render(&base, s, &params, &lod)?;
+        removed += frame_removed;
+        let path = if args.animate {
+            format!("{}_{:04}.{}", output, i + 1, format.extension())
+        } else {
+            format!("{}.{}", output, format.extension())
+        };
+        write(format, args.render.shading, (meshes, objects), &palette, scale, &path, &mtl)
+    })?;
+    println!("Merged segments: {} removed", removed);
+
+    //branch graph of the last derivation step
+    if let Some(path) = &args.render.topology {
+        let (segments, _, _) = engine::interpret(to_input(&base, &res), &params)?;
+        Topology::new(&engine::process_segments(segments).0).write(path)?;
+        println!("Successfully wrote to {}", path);
+    }
+
+    Ok(())
+}
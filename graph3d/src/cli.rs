@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::fs;
+use clap::Args;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use crate::engine::{self, Lod, Params};
+use crate::mesh::Shading;
+
+//rendering flags shared by graph3d and plants3d
+#[derive(Args)]
+pub struct RenderArgs {
+    /// Default rotation angle in degrees, overrides #angle
+    #[arg(short, long)]
+    pub angle: Option<f64>,
+
+    /// Default length of F and f, overrides #step
+    #[arg(short, long)]
+    pub step: Option<f64>,
+
+    /// Width of the thinnest and of the thickest branches, overrides #width
+    #[arg(short, long, num_args = 2, value_names = ["MIN", "MAX"])]
+    pub width: Option<Vec<f64>>,
+
+    /// Width ratio applied by ! without parameter, in ]0, 1], overrides #shrink
+    #[arg(long, value_parser = shrink_ratio)]
+    pub shrink: Option<f64>,
+
+    /// Number of sides of the branch tubes, from MIN for the thinnest branches
+    /// to MAX for the thickest ones
+    #[arg(long, num_args = 1..=2, value_names = ["MIN", "MAX"], default_values_t = [6],
+          value_parser = clap::value_parser!(u64).range(3..))]
+    pub sides: Vec<u64>,
+
+    /// Leave out the branches thinner than WIDTH
+    #[arg(long, value_name = "WIDTH")]
+    pub thin: Option<f64>,
+
+    /// Draw the branches thinner than --thin as lines instead of leaving them out
+    #[arg(long, requires = "thin")]
+    pub lines: bool,
+
+    /// Also write the branch graph to FILE, as GraphML if it ends with .graphml, JSON otherwise
+    #[arg(long, value_name = "FILE")]
+    pub topology: Option<String>,
+
+    /// Palette file, one "index rrggbb" entry per line, overrides #color
+    #[arg(short, long, value_name = "FILE")]
+    pub colors: Option<String>,
+
+    /// Format of the output, OBJ with a material library or binary glTF
+    #[arg(short, long, default_value = "obj",
+          value_parser = PossibleValuesParser::new(["obj", "glb"])
+              .map(|s: String| Format::from(&s).unwrap()))]
+    pub format: Format,
+
+    /// Normals of the faces, smooth uses vertex normals
+    #[arg(long, default_value = "smooth",
+          value_parser = PossibleValuesParser::new(["flat", "smooth"])
+              .map(|s: String| Shading::from(&s).unwrap()))]
+    pub shading: Shading,
+}
+
+//formats of the generated geometry
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Obj,//OUTPUT.obj and OUTPUT.mtl
+    Glb
+}
+
+impl Format {
+    pub fn from(s: &str) -> Option<Format> {
+        match s {
+            "obj" => Some(Format::Obj),
+            "glb" => Some(Format::Glb),
+            _ => None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Obj => "obj",
+            Format::Glb => "glb"
+        }
+    }
+}
+
+fn shrink_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(r) if r > 0.0 && r <= 1.0 => Ok(r),
+        _ => Err(String::from("expected a number in ]0, 1]"))
+    }
+}
+
+impl RenderArgs {
+    //overrides given to engine::read_str, fails if the palette file is invalid
+    pub fn params(&self) -> Result<Params, String> {
+        let colors = match &self.colors {
+            Some(file) => {
+                let s = fs::read_to_string(file)
+                    .map_err(|e| format!("could not read {}: {}", file, e))?;
+                engine::read_palette(&s).map_err(|e| format!("{}: {}", file, e))?
+            },
+            None => BTreeMap::new()
+        };
+
+        Ok(Params{
+            angle: self.angle,
+            dist: self.step,
+            width: self.width.as_ref().map(|w| (w[0], w[1])),
+            shrink: self.shrink,
+            colors
+        })
+    }
+
+    //level of detail of the branches, widths are the ones of the thinnest and thickest branches
+    pub fn lod(&self, widths: (f64, f64)) -> Lod {
+        let min = self.sides[0] as usize;
+        Lod{
+            sides: (min, self.sides.get(1).map_or(min, |max| *max as usize)),
+            widths,
+            thin: self.thin.unwrap_or(0.0),
+            lines: self.lines
+        }
+    }
+}
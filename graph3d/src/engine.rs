@@ -7,8 +7,9 @@ use crate::vector3;
 use turtle::Turtle;
 use mesh::Mesh;
 use vector3::Vector3;
-use std::collections::HashMap;
-use crate::object::Object;
+use std::collections::{BTreeMap, HashMap};
+use crate::object::{Instances, Object};
+use serde::Deserialize;
 
 
 #[derive(Clone, Copy)]
@@ -16,7 +17,10 @@ pub struct Segment {
     pub a : Turtle,
     pub b : Turtle,
     pub width : f64,
-    pub color_i : i64
+    pub color_i : i64,
+    pub length : f64,//length of the branch from the root to a
+    pub parent : Option<usize>,//segment ending at a, None after a move without drawing
+    pub branch : bool//first segment of a [ ] branch, not a continuation of its parent
 }
 
 impl Segment {
@@ -40,15 +44,10 @@ impl Segment {
         ).normalized()
     }
 
+    //s starts where self ends and goes in the same direction
     pub fn collinear(&self, s : Segment, epsilon : f64) -> bool {
-        let self_dir : Vector3 = self.build_dir();
-        let s_dir : Vector3 = s.build_dir();
-        let dir3 = s.b.pos() - self.a.pos();
-        let dot_align = dir3.dot(self_dir);
-
-        let prod = self_dir.dot(s_dir);
-        prod >= 1.0 - epsilon && prod <= 1.0 + epsilon
-        && dot_align >= 1.0 - epsilon && dot_align <= 1.0 + epsilon
+        (s.a.pos() - self.b.pos()).norm() <= epsilon
+            && self.build_dir().dot(s.build_dir()) >= 1.0 - epsilon
     }
 
     pub fn size_eq(&self, s : &Segment, epsilon : f64) -> bool {
@@ -76,7 +75,8 @@ fn get_parameter(s : &str, i : usize, len : usize) -> (&str, usize) {
     (parameter, e)
 }
 
-pub fn read_header(s: &str, i: usize) -> (usize, HashMap<String, mesh::Mesh>) {
+//read the '#' header line of the objects, as name and file pairs
+pub fn read_header(s: &str, i: usize) -> (usize, HashMap<String, String>) {
     let mut i = i;
     if s.as_bytes()[i] as char == '#' {
         let mut lines = s.lines();
@@ -91,16 +91,15 @@ pub fn read_header(s: &str, i: usize) -> (usize, HashMap<String, mesh::Mesh>) {
         while let Some(object_name) = split.next() {
             let object_name = object_name.chars().collect();
 
-            let object_mesh = match split.next() {
-                Some(s) => Mesh::load(&s.to_string()),
+            let object_file = match split.next() {
+                Some(s) => s.to_string(),
                 _ => return (i, HashMap::new())
             };
 
-            //println!("{}", object_mesh.clone().get_str());
-            map.insert(object_name, object_mesh);
+            map.insert(object_name, object_file);
         }
 
-        while s.as_bytes()[i] as char != '\n' {
+        while i < s.len() && s.as_bytes()[i] as char != '\n' {
             i += 1;
         }
         (i, map)
@@ -109,88 +108,408 @@ pub fn read_header(s: &str, i: usize) -> (usize, HashMap<String, mesh::Mesh>) {
     }
 }
 
-pub fn read_tropism(s: &str) -> (usize, Vector3, f64) {
+//read the '@' header line of the tropism, as x, y, z and strength
+pub fn read_tropism(s: &str) -> Result<(usize, Option<[f64; 4]>), String> {
     let mut i = 0;
-    if s.as_bytes()[0] as char == '@' {
+    if s.starts_with('@') {
         let mut lines = s.lines();
         //get only the first line and split it
         let mut split = match lines.nth(0) {
             Some(l) => l.trim().trim_start_matches('@').split(" "),
-            None => return (0, Vector3::new(0.0,0.0,0.0), 0.0)
+            None => return Ok((0, None))
         };
 
-        let mut data = Vec::new();
-        for _ in 0..4 {
+        let mut data = [0.0; 4];
+        for d in &mut data {
             let f_str = match split.next() {
                 Some(f) => {f},
                 _ => "0.0"
             };
-            let f = f_str.parse::<f64>().expect("Invalid tropism data");
+            let f = f_str.parse::<f64>()
+                .map_err(|_| format!("invalid tropism `{}`", f_str))?;
 
-            data.push(f);
+            *d = f;
         }
 
-        while s.as_bytes()[i] as char != '\n' {
+        while i < s.len() && s.as_bytes()[i] as char != '\n' {
             i += 1;
         }
-        let v = Vector3::new(data[0], data[1], data[2]);
-        let a = data[3];
-        (i, v, a)
+        Ok((i, Some(data)))
     } else {
-        (0, Vector3::new(0.0,0.0,0.0), 0.0)
+        Ok((0, None))
+    }
+}
+
+//read the ':' header line listing the multi-character module names, if any.
+//Names are sorted from the longest to the shortest for matching.
+pub fn read_modules(s: &str, i: usize) -> (usize, Vec<String>) {
+    let start = if s[i..].starts_with('\n') {i + 1} else {i};
+    if !s[start..].starts_with(':') {
+        return (i, Vec::new());
+    }
+
+    let end = match s[start..].find('\n') {
+        Some(e) => start + e,
+        None => s.len()
+    };
+    let mut modules: Vec<String> = s[start + 1..end].split_whitespace()
+        .map(|m| m.to_string())
+        .collect();
+    modules.sort_by_key(|m| std::cmp::Reverse(m.len()));
+
+    (end, modules)
+}
+
+//read the '=' header line holding the rendering parameters declared in the grammar
+pub fn read_params(s: &str, i: usize) -> Result<(usize, Params), String> {
+    let start = if s[i..].starts_with('\n') {i + 1} else {i};
+    if !s[start..].starts_with('=') {
+        return Ok((i, Params::default()));
+    }
+
+    let end = match s[start..].find('\n') {
+        Some(e) => start + e,
+        None => s.len()
+    };
+    let words: Vec<&str> = s[start + 1..end].split_whitespace().collect();
+    //k - 1 is the parameter name, or the previous value
+    let value = |k: usize| -> Result<f64, String> {
+        words.get(k).and_then(|w| w.parse().ok())
+            .ok_or_else(|| format!("expected a number after `{}`", words[k - 1]))
+    };
+
+    let mut params = Params::default();
+    let mut k = 0;
+    while k < words.len() {
+        match words[k] {
+            "angle" => {params.angle = Some(value(k + 1)?); k += 2;},
+            "step" => {params.dist = Some(value(k + 1)?); k += 2;},
+            "width" => {params.width = Some((value(k + 1)?, value(k + 2)?)); k += 3;},
+            "shrink" => {params.shrink = Some(value(k + 1)?); k += 2;},
+            "color" => {
+                let i = value(k + 1)? as usize;
+                params.colors.insert(i, words.get(k + 2).unwrap_or(&"").to_string());
+                k += 3;
+            },
+            _ => {k += 1;}//unknown parameter
+        }
+    }
+
+    Ok((end, params))
+}
+
+//level of detail of the branch tubes
+#[derive(Clone, Copy, Debug)]
+pub struct Lod {
+    pub sides : (usize, usize),//number of sides of the thinnest and of the thickest branches
+    pub widths : (f64, f64),//width of the thinnest and of the thickest branches
+    pub thin : f64,//branches thinner than this are not tubes
+    pub lines : bool//thin branches are drawn as lines, or left out
+}
+
+impl Lod {
+    //same number of sides for all the branches
+    pub fn new(sides : usize) -> Lod {
+        Lod{sides: (sides, sides), widths: (0.0, 0.0), thin: 0.0, lines: false}
+    }
+
+    //sides go linearly from the thinnest to the thickest branches
+    pub fn sides(&self, width : f64) -> usize {
+        let (min, max) = self.widths;
+        let k = if max > min {((width - min) / (max - min)).clamp(0.0, 1.0)} else {1.0};
+        let (a, b) = (self.sides.0 as f64, self.sides.1 as f64);
+
+        (a + k * (b - a)).round() as usize
+    }
+}
+
+//a module of the derived string, as read from the text or the JSON output of plants
+pub struct Module {
+    pub sym : String,
+    pub params : Vec<f64>,
+    pub object : Option<String>//name of the object drawn by ~
+}
+
+//rendering parameters, None when not given
+#[derive(Clone, Default, Debug, Deserialize)]
+pub struct Params {
+    pub angle : Option<f64>,//degrees
+    #[serde(rename = "step")]
+    pub dist : Option<f64>,
+    pub width : Option<(f64, f64)>,//min and max
+    pub shrink : Option<f64>,//width reason of !
+    #[serde(default)]
+    pub colors : BTreeMap<usize, String>//palette entries as rrggbb
+}
+
+impl Params {
+    //parameters of self, completed by the ones of other
+    pub fn or(self, other : Params) -> Params {
+        let mut colors = other.colors;
+        colors.extend(self.colors);
+        Params{
+            angle: self.angle.or(other.angle),
+            dist: self.dist.or(other.dist),
+            width: self.width.or(other.width),
+            shrink: self.shrink.or(other.shrink),
+            colors
+        }
+    }
+
+    //fails if the shrink reason is not in ]0, 1] or if a color is not rrggbb
+    pub fn check(&self) -> Result<(), String> {
+        if let Some(r) = self.shrink.filter(|r| *r <= 0.0 || *r > 1.0) {
+            return Err(format!("expected a shrink reason in ]0, 1], found {}", r));
+        }
+        match self.colors.values().find(|c| parse_color(c).is_none()) {
+            Some(c) => Err(format!("expected a color as rrggbb, found `{}`", c)),
+            None => Ok(())
+        }
+    }
+
+    //width of the thinnest and of the thickest branches
+    pub fn widths(&self) -> (f64, f64) {
+        self.width.unwrap_or((0.1, 0.5))
+    }
+
+    //colors by color index. Without declared entries, this is default_colors,
+    //otherwise it goes up to the last declared index, other entries are taken from the defaults.
+    //Invalid entries, see check, are also taken from the defaults
+    pub fn palette(&self) -> Vec<(f64, f64, f64)> {
+        let defaults = default_colors();
+        let len = match self.colors.keys().next_back() {
+            Some(i) => i + 1,
+            None => defaults.len()
+        };
+
+        (0..len)
+            .map(|i| match self.colors.get(&i) {
+                Some(hex) => parse_color(hex).unwrap_or(defaults[i % defaults.len()]),
+                None => defaults[i % defaults.len()]
+            })
+            .collect()
+    }
+}
+
+//rrggbb to RGB in [0, 255], a leading # is allowed
+pub fn parse_color(hex : &str) -> Option<(f64, f64, f64)> {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |k : usize| u8::from_str_radix(&hex[k..k + 2], 16).ok().map(f64::from);
+
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+//read a palette file, one "index rrggbb" entry per line
+pub fn read_palette(s : &str) -> Result<BTreeMap<usize, String>, String> {
+    let mut colors = BTreeMap::new();
+    for (n, l) in s.lines().enumerate() {
+        let words : Vec<&str> = l.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {},
+            [i, hex] => {
+                match (i.parse::<usize>(), parse_color(hex)) {
+                    (Ok(i), Some(_)) => {colors.insert(i, hex.trim_start_matches('#').to_string());},
+                    _ => {return Err(format!("line {}: expected `index rrggbb`, found `{}`", n + 1, l));}
+                }
+            },
+            _ => {return Err(format!("line {}: expected `index rrggbb`, found `{}`", n + 1, l));}
+        }
     }
+
+    Ok(colors)
+}
+
+#[derive(Deserialize)]
+struct JsonModule {
+    sym : String,
+    params : Vec<f64>,
+    rule_set : String,
+    object : bool
+}
+
+//data written before the modules in the JSON output of plants
+#[derive(Deserialize, Default)]
+pub struct Header {
+    pub tropism : Option<[f64; 4]>,//x, y, z, strength
+    pub objects : HashMap<String, String>,//name in the output -> file
+    #[serde(default)]
+    pub render : Params
+}
+
+#[derive(Deserialize)]
+struct JsonOutput {
+    #[serde(flatten)]
+    header : Header,
+    modules : Vec<JsonModule>
+}
+
+//everything read from the output of plants
+pub struct Input {
+    pub tropism : (Vector3, f64),//vector and strength
+    pub meshes : HashMap<String, Mesh>,
+    pub modules : Vec<Module>,
+    pub params : Params//declared in the grammar
 }
 
-pub fn read_str(s : &str,
-                dist : f64,
-                angle : f64,
-                d_limits : (f64, f64),
-                d_reason : f64,
-                nb_colors : i64) -> (Vec<Segment>, Vec<Leaf>, Vec<Object>) {
-    if d_reason > 1.0 {
-        panic!("Invalid reason.");
+impl Input {
+    //input with the data of header, object meshes are loaded from their files
+    pub fn new(header : Header, modules : Vec<Module>) -> Result<Input, String> {
+        header.render.check()?;
+        let tropism = match header.tropism {
+            Some(t) => (Vector3::new(t[0], t[1], t[2]), t[3]),
+            None => (Vector3::new(0.0, 0.0, 0.0), 0.0)
+        };
+        let meshes = header.objects.iter()
+            .map(|(name, file)| Ok((name.clone(), Mesh::load(file)?)))
+            .collect::<Result<_, String>>()?;
+
+        Ok(Input{tropism, meshes, modules, params: header.render})
     }
+}
+
+fn read_json(s : &str) -> Result<Input, String> {
+    let output : JsonOutput = serde_json::from_str(s)
+        .map_err(|e| format!("invalid JSON input: {}", e))?;
+    let modules = output.modules.into_iter()
+        .map(|m| {
+            if m.object {
+                Module{sym: String::from("~"), params: Vec::new(),
+                    object: Some(format!("{}{}", m.rule_set, m.sym))}
+            } else {
+                Module{sym: m.sym, params: m.params, object: None}
+            }
+        })
+        .collect();
+
+    Input::new(output.header, modules)
+}
+
+fn read_text(s : &str) -> Result<Input, String> {
+    let len = s.len();
+
+    //read header
+    let (i, tropism) = read_tropism(s)?;
+    let i = if i < len && (s.as_bytes()[i] as char) == '\n' {i + 1} else {i};
+    let (i, objects) = if i < len {read_header(s, i)} else {(i, HashMap::new())};
+    let (i, names) = if i < len {read_modules(s, i)} else {(i, Vec::new())};
+    let (mut i, render) = if i < len {read_params(s, i)?} else {(i, Params::default())};
+
+    let mut modules = Vec::new();
+    while i < len {
+        let sym = match names.iter().find(|m| s[i..].starts_with(m.as_str())) {
+            Some(m) => m.clone(),
+            None => (s.as_bytes()[i] as char).to_string()
+        };
+        i += sym.len();
+
+        let mut params = Vec::new();
+        let mut object = None;
+        // Check for ( parameter
+        if i < len && (s.as_bytes()[i] as char) == '(' {
+            let (parameter, e) = get_parameter(s, i + 1, len);
+            if sym == "~" {
+                object = Some(parameter.to_string());
+            } else {
+                params = parameter.split(',')
+                    .map(|p| p.parse().map_err(|_| format!("invalid parameter `{}` of {}", p, sym)))
+                    .collect::<Result<_, String>>()?;
+            }
+            i = e + 1;
+        }
+
+        modules.push(Module{sym, params, object});
+    }
+
+    Input::new(Header{tropism, objects, render}, modules)
+}
+
+//parameter given in degrees, or default angle in radians
+fn get_angle(m : &Module, angle : f64) -> f64 {
+    match m.params.first() {
+        Some(a) => (a * PI) / 180.0,
+        None => angle
+    }
+}
+
+//available colors, meshes are indexed with their color index
+pub fn default_colors() -> Vec<(f64, f64, f64)> {
+    vec![
+        (90.0, 35.0, 35.0),    // 5a2323, brown
+        (118.0, 156.0, 35.0),  // 769c23, green
+        (118.0, 156.0, 35.0)//third color just to add more possibilities
+    ]
+}
+
+//read the output of plants, either text or JSON.
+//Text can also start with '{' when the axiom opens a polygon
+pub fn read_input(s : &str) -> Result<Input, String> {
+    let json = s.trim_start().strip_prefix('{')
+        .is_some_and(|r| r.trim_start().starts_with('"'));
+    if json {
+        read_json(s)
+    } else {
+        read_text(s)
+    }
+}
+
+//read the output of plants and run the turtle on it.
+//params overrides the rendering parameters of the input header
+pub fn read_str(s : &str, params : Params) -> Result<(Vec<Segment>, Vec<Leaf>, Instances), String> {
+    let mut input = read_input(s)?;
+    let params = params.or(std::mem::take(&mut input.params));
+
+    let (segments, leaves, objects) = interpret(input, &params)?;
+    Ok((process_segments(segments).0, leaves, objects))
+}
+
+//run the turtle on the modules, ignoring the parameters of the input.
+//Parameters not given in params take their default value. One segment is made per F,
+//see process_segments to merge them. Fails if params are invalid, see Params::check
+pub fn interpret(input : Input, params : &Params) -> Result<(Vec<Segment>, Vec<Leaf>, Instances), String> {
+    params.check()?;
+    let nb_colors = params.palette().len() as i64;
+    let dist = params.dist.unwrap_or(1.0);
+    let angle = params.angle.unwrap_or(22.5) * (PI / 180.0);
+    let d_limits = params.widths();
+    let d_reason = params.shrink.unwrap_or(0.8);
+
+    let (tropism_vec, tropism_a) = input.tropism;
+    let mut mesh_map = input.meshes;
+    let modules = input.modules;
 
     let mut current_color_i = 0;
     let mut color_stack = Vec::new();
 
     let mut t = Turtle::new();
     let mut stack : Vec<Turtle> = Vec::with_capacity(10);
+    let mut length = 0.0;
+    let mut length_stack = Vec::new();
+    let mut parent = None;//last segment drawn and if the next one starts a branch
+    let mut branch = false;
+    let mut parent_stack = Vec::new();
     let mut leaf_mode = 0;  // If true, we are creating a leaf
 
     let mut segments : Vec<Segment> = Vec::new();
     let mut leaves : Vec<Leaf> = Vec::new();
-    let mut objects : Vec<Object> = Vec::new();
+    let mut objects = Instances::default();
 
     let max_d_delta = d_limits.1 - d_limits.0;//max - min
 
     let mut tmp_leaf = Leaf{pts: Vec::new(), color_i: current_color_i};
     let mut leaf_stack: Vec<Leaf> = Vec::with_capacity(5);
 
-    let len = s.len();
-
-    //read header
-    let (mut i, tropism_vec, tropism_a) = read_tropism(s);
-    let (mut i, mesh_map) = read_header(s, i);
-
-    while i < len {
-        // Read characters and add data to the output file
-        // Characters:
+    for m in &modules {
+        // Read modules and add data to the output file
+        // Modules:
         // - Basic movements in space: +-&^\/|fF
         // - Branches: [(push state)](pop state)
         // - Leaves: {(start polygon)}(end polygon)
-        // let mut b: u8 = s.as_bytes()[i];
-        // let c : char = b as char;
-        match s.as_bytes()[i] as char {
-            'F' => {
-                let mut new_dist = dist as f64;
-
-                // Check for ( parameter
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    new_dist = parameter.parse().unwrap();
-                    i = e;
-                }
+        match m.sym.as_str() {
+            "F" => {
+                let new_dist = *m.params.first().unwrap_or(&dist);
 
                 //tropism. We do it before because it's more logical
                 if tropism_a < -0.0001 || tropism_a > 0.0001 {
@@ -208,259 +527,259 @@ pub fn read_str(s : &str,
                 let b = t.clone();
 
                 segments.push(
-                    Segment{a, b, width : d_limits.0 + t.size() * max_d_delta, color_i: current_color_i}
+                    Segment{a, b, width : d_limits.0 + t.size() * max_d_delta, color_i: current_color_i,
+                            length, parent, branch}
                 );
+                length += new_dist;
+                parent = Some(segments.len() - 1);
+                branch = false;
             },  // Place two points
-            'f' => {
-                let mut new_dist = dist as f64;
-
-                // Check for ( parameter
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    new_dist = parameter.parse().unwrap();
-                    i = e;
-                }
-
-                t.forward(new_dist);
+            "f" => {
+                t.forward(*m.params.first().unwrap_or(&dist));
+                parent = None;
             },  // Only move except if we are creating a leaf
-            '+' => {
-                let mut new_angle = angle as f64;
-
-                // Check for ( parameter
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    new_angle = parameter.parse().unwrap();
-                    new_angle = (new_angle * PI) / 180.0;
-                    i = e;
-                }
-
-                t.rot_yaw(new_angle);
-            },
-            '-' => {
-                let mut new_angle = angle as f64;
-
-                // Check for ( parameter
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    new_angle = parameter.parse().unwrap();
-                    new_angle = (new_angle * PI) / 180.0;
-                    i = e;
-                }
-
-                t.rot_yaw(-new_angle);
-            },
-            '&' => {
-                let mut new_angle = angle as f64;
-
-                // Check for ( parameter
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    new_angle = parameter.parse().unwrap();
-                    new_angle = (new_angle * PI) / 180.0;
-                    i = e;
-                }
-
-                t.rot_pitch(new_angle);
-            },
-            '^' => {
-                let mut new_angle = angle as f64;
-
-                // Check for ( parameter
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    new_angle = parameter.parse().unwrap();
-                    new_angle = (new_angle * PI) / 180.0;
-                    i = e;
-                }
-
-                t.rot_pitch(-new_angle);
-            },
-            '\\' => {
-                let mut new_angle = angle as f64;
-
-                // Check for ( parameter
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    new_angle = parameter.parse().unwrap();
-                    new_angle = (new_angle * PI) / 180.0;
-                    i = e;
-                }
-
-                t.rot_roll(new_angle);
-            },
-            '/' => {
-                let mut new_angle = angle as f64;
-
-                // Check for ( parameter
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    new_angle = parameter.parse().unwrap();
-                    new_angle = (new_angle * PI) / 180.0;
-                    i = e;
-                }
-
-                t.rot_roll(-new_angle);
-            },
-            '|' => {t.rot_yaw(PI);},
-            '[' => {
+            "+" => {t.rot_yaw(get_angle(m, angle));},
+            "-" => {t.rot_yaw(-get_angle(m, angle));},
+            "&" => {t.rot_pitch(get_angle(m, angle));},
+            "^" => {t.rot_pitch(-get_angle(m, angle));},
+            "\\" => {t.rot_roll(get_angle(m, angle));},
+            "/" => {t.rot_roll(-get_angle(m, angle));},
+            "|" => {t.rot_yaw(PI);},
+            "[" => {
                 stack.push(t.clone());
                 color_stack.push(current_color_i);
+                length_stack.push(length);
+                parent_stack.push((parent, branch));
+                branch = true;
             },
-            ']' => {
+            "]" => {
                 t = stack.pop().unwrap_or(t);
                 current_color_i = color_stack.pop().unwrap_or(0);
+                length = length_stack.pop().unwrap_or(length);
+                (parent, branch) = parent_stack.pop().unwrap_or((parent, branch));
             },
-            '{' => {
+            "{" => {
                 leaf_stack.push(tmp_leaf.clone());
                 leaf_mode += 1;
                 tmp_leaf = Leaf{pts: Vec::new(), color_i: current_color_i};
             },  // :smirk:
-            '}' => {
+            "}" => {
                 leaf_mode -= 1;
                 //tmp_leaf.pts.push(t.pos().clone());
                 leaves.push(tmp_leaf.clone());
                 tmp_leaf = leaf_stack.pop().unwrap_or(Leaf{pts: Vec::new(), color_i: current_color_i});
             },
-            '!' => {
-                let mut has_parameter = false;
-
-                // Check for ( parameter
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    has_parameter = true;
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    t.set_size(parameter.parse().unwrap());
-                    i = e;
-                }
-
-                if !has_parameter {
-                    t.decrease(d_reason);
+            "!" => {
+                match m.params.first() {
+                    Some(size) => {t.set_size(*size);},
+                    None => {t.decrease(d_reason);}
                 }
             },
-            '\'' => {
-                current_color_i += 1;
-                current_color_i %= nb_colors;
+            "'" => {
+                match m.params.first() {
+                    Some(i) => {current_color_i = (*i as i64).rem_euclid(nb_colors);},
+                    None => {
+                        current_color_i += 1;
+                        current_color_i %= nb_colors;
+                    }
+                }
             },
-            '$' => {
+            "$" => {
                 let minus_g = Vector3::new(0f64, 0f64, 1f64);
                 let new_left = minus_g.cross(t.heading()).normalized();
                 let new_up = t.heading().cross(new_left);
                 t = Turtle::new_param(t.pos(), t.heading(), new_left,
                 new_up, t.size());
             },
-            '~' => {
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let (parameter, e) = get_parameter(s, i + 2, len);
-                    match mesh_map.get(parameter) {
-                        Some(mesh) => {
-                            objects.push(Object::new(mesh.clone(), t));
-                        },
-                        _ => {}
+            "~" => {
+                //the mesh is moved to the instances when the alias is first placed
+                if let Some(name) = m.object.as_ref() {
+                    if let Some(mesh) = mesh_map.remove(name) {
+                        objects.meshes.insert(name.clone(), mesh);
+                    }
+                    if objects.meshes.contains_key(name) {
+                        objects.objects.push(Object::new(name.clone(), t));
                     }
-                    i = e;
                 }
             },
-            '.' => {
+            "." => {
                 if leaf_mode == 0 {
                     println!("ERROR : dot found out of leaf.");
                 }
                 tmp_leaf.pts.push(t.pos().clone());
             },
-            _ => {  // Unknown char : do nothing & ignore parameters, if any
-                if i + 1 < len && (s.as_bytes()[i+1] as char) == '(' {
-                    let mut e = i + 1;
-                    while e < len && (s.as_bytes()[e] as char) != ')' {
-                        e += 1;
-                    }
-                    i = e;
-                }
-            }
+            _ => {}  // Unknown module : do nothing
         }
-
-        i += 1;
     }
 
-    (process_segments(segments), leaves, objects)
+    Ok((segments, leaves, objects))
 }
 
 
-fn process_segments(segments : Vec<Segment>) -> Vec<Segment> {
+//merge the chains of collinear segments of the same width and color, following the
+//branches: a segment is merged in its parent if it continues it and the parent has no other
+//child, so branch points are kept. Returns the segments and the number of removed ones
+pub fn process_segments(segments : Vec<Segment>) -> (Vec<Segment>, usize) {
+    let mut children = vec![0; segments.len()];
+    for s in &segments {
+        if let Some(p) = s.parent {
+            children[p] += 1;
+        }
+    }
+
     let mut new_segments : Vec<Segment> = Vec::new();
+    let mut new_index = vec![0; segments.len()];//index of the segment a segment is merged in
+    for (i, s) in segments.iter().enumerate() {
+        let merged = match s.parent {
+            Some(p) => !s.branch && children[p] == 1 && segments[p].color_i == s.color_i
+                && segments[p].size_eq(s, 0.001) && segments[p].collinear(*s, 0.001),
+            None => false
+        };
 
-    let mut i = 0;
-    while i < segments.len() {
-        let mut j = 1;
-        while i + j < segments.len() && segments[i].collinear(segments[i + j], 0.001)
-            && segments[i].size_eq(&segments[i + j], 0.001) {
-            j += 1;
+        if merged {
+            let head = new_index[s.parent.unwrap()];
+            new_segments[head].b = s.b();
+            new_index[i] = head;
+        } else {
+            new_index[i] = new_segments.len();
+            new_segments.push(Segment{parent: s.parent.map(|p| new_index[p]), ..*s});
         }
-
-        let end_turtle = Turtle::new_param( segments[i + j - 1].b().pos(),
-                                            segments[i].a().heading(),
-                                            segments[i].a().left(),
-                                            segments[i].a().up(),
-                                            segments[i].a().size());
-
-        new_segments.push(
-            Segment{
-                            a : segments[i].a(),
-                            b : end_turtle,
-                            width : segments[i].width(),
-                            color_i : segments[i].color_i
-            }
-        );
-        i += j;
     }
 
-    new_segments
+    let removed = segments.len() - new_segments.len();
+    (new_segments, removed)
 }
 
 pub fn gen_geometry(segments : Vec<Segment>, leaves : Vec<Leaf>,
-                    objects : Vec<Object>, nb_colors: i64) -> Vec<Mesh> {
+                    objects : Instances, nb_colors: i64, lod : &Lod) -> Vec<Mesh> {
+    let mut meshes = gen_meshes(segments, leaves, nb_colors, lod);
+    merge_objects(&mut meshes, &objects);
 
-    let mut meshes : Vec<Mesh> = Vec::new();
+    meshes
+}
 
-    for _ in 0..nb_colors {
-        meshes.push(Mesh::new());
+//chains of segments drawn as a single tube: a segment continues its parent
+//unless it starts a branch or changes color. Only the segments with tube set are in chains
+fn chains(segments : &[Segment], tube : &[bool]) -> Vec<Vec<usize>> {
+    let mut next : Vec<Option<usize>> = vec![None; segments.len()];
+    let mut starts = Vec::new();
+    for (i, s) in segments.iter().enumerate() {
+        if !tube[i] {
+            continue;
+        }
+        match s.parent {
+            Some(p) if tube[p] && !s.branch && next[p].is_none()
+                && segments[p].color_i == s.color_i => {
+                next[p] = Some(i);
+            },
+            _ => starts.push(i)
+        }
     }
 
-    for s in segments {
-        let mut top : Vec<usize> = Vec::new();  // Top vertices
-        let mut bot : Vec<usize> = Vec::new();  // Bottom vertices
+    starts.iter()
+        .map(|start| {
+            let mut chain = vec![*start];
+            while let Some(n) = next[chain[chain.len() - 1]] {
+                chain.push(n);
+            }
+            chain
+        })
+        .collect()
+}
+
+//tube swept along a chain of segments, with a ring of sides + 1 vertices at each joint
+//(texture coordinates wrap around the tube, the first vertex is repeated at u = 1).
+//Rings are rotated from one joint to the next with the double reflection method so that
+//the tube does not twist, widths go from the one of a segment to the one of the next
+fn sweep(mesh : &mut Mesh, segments : &[Segment], chain : &[usize], sides : usize) {
+    let first = segments[chain[0]];
+    let last = segments[chain[chain.len() - 1]];
+
+    //joints with their tangent, width and distance from the root
+    let mut joints = vec![(first.a().pos(), first.a().heading(), first.width(), first.length)];
+    for (k, i) in chain.iter().enumerate() {
+        let s = segments[*i];
+        let (tangent, width) = match chain.get(k + 1) {
+            Some(n) => {
+                let t = s.build_dir() + segments[*n].build_dir();
+                (if t.norm() > 0.0001 {t.normalized()} else {s.build_dir()}, segments[*n].width())
+            },
+            None => (s.b().heading(), s.width())
+        };
+        joints.push((s.b().pos(), tangent, width, s.length + (s.b().pos() - s.a().pos()).norm()));
+    }
 
-        let current_color_i : usize = s.color_i as usize;
+    let mut rings : Vec<Vec<usize>> = Vec::new();
+    let mut r = first.a().up();
+    for (k, (p, t, width, v)) in joints.iter().enumerate() {
+        if k > 0 {
+            let (p0, t0, _, _) = joints[k - 1];
+            let v1 = *p - p0;
+            let c1 = v1.dot(v1);
+            if c1 > 0.0 {
+                let r_l = r - v1 * (2.0 / c1 * v1.dot(r));
+                let t_l = t0 - v1 * (2.0 / c1 * v1.dot(t0));
+                let v2 = *t - t_l;
+                let c2 = v2.dot(v2);
+                r = if c2 > 0.0 {r_l - v2 * (2.0 / c2 * v2.dot(r_l))} else {r_l};
+            }
+        }
 
-        //println!("{:?}", s.a);
-        let nb_face = 6;
-        for i in 0..nb_face {  // Generate hexagons
-            let mut rot = s.a().clone();
-            rot.rot_roll((2.0 * PI / (nb_face as f64)) * (i as f64));
-            //println!("{:?}", rot);
-            let p = rot.pos() + rot.up() * (s.width() / 2.0);  // Place point
-            top.push(meshes[current_color_i].add_vert(&p));
+        let left = t.cross(r);
+        rings.push((0..=sides)
+            .map(|i| {
+                let a = 2.0 * PI * i as f64 / sides as f64;
+                let n = r * a.cos() + left * a.sin();  // Radial normal
+                mesh.add_vert_with(&(*p + n * (width / 2.0)), &n, [i as f64 / sides as f64, *v])
+            })
+            .collect());
+    }
 
-            let mut rot = s.b.clone();
-            rot.rot_roll((2.0 * PI / (nb_face as f64)) * (i as f64));
-            let p = rot.pos() + rot.up() * (s.width() / 2.0);
-            bot.push(meshes[current_color_i].add_vert(&p));
+    for (top, bot) in rings.iter().zip(rings.iter().skip(1)) {
+        for i in 0..sides {
+            mesh.add_face(top[i], top[i + 1], bot[i]);
+            mesh.add_face(top[i + 1], bot[i + 1], bot[i]);
         }
+    }
+
+    //pointed caps at both ends
+    let (p, _, width, v) = joints[0];
+    let e1 = mesh.add_vert_with(&(p - first.a().heading() * (width / 2.0)),
+                                &(first.a().heading() * -1.0), [0.5, v]);
+    let (p, _, width, v) = joints[joints.len() - 1];
+    let e2 = mesh.add_vert_with(&(p + last.b().heading() * (width / 2.0)), &last.b().heading(), [0.5, v]);
+    let (top, bot) = (&rings[0], &rings[rings.len() - 1]);
+    for i in 0..sides {
+        mesh.add_face(top[i], e1, top[i + 1]);
+        mesh.add_face(bot[i + 1], e2, bot[i]);
+    }
+}
+
+//branches and leaves, without the objects. The number of sides of a tube is given
+//by its thickest segment
+pub fn gen_meshes(segments : Vec<Segment>, leaves : Vec<Leaf>, nb_colors: i64, lod : &Lod) -> Vec<Mesh> {
 
-        let e1 = s.a().pos() - s.a().heading() * (s.width() / 2.0);
-        let e2 = s.b().pos() + s.b().heading() * (s.width() / 2.0);
-        let e1 = meshes[current_color_i].add_vert(&e1);
-        let e2 = meshes[current_color_i].add_vert(&e2);
+    let mut meshes : Vec<Mesh> = Vec::new();
 
-        // We now have all points placed, we need to set faces
-        for i in 0..nb_face {
-            let a_t = i;
-            let b_t = (i + 1) % nb_face;
-            let a_b = i;
-            let b_b = (i + 1) % nb_face;
+    for _ in 0..nb_colors {
+        meshes.push(Mesh::new());
+    }
 
-            meshes[current_color_i].add_face(top[a_t], top[b_t], bot[a_b]);
-            meshes[current_color_i].add_face(top[b_t], bot[b_b], bot[a_b]);
+    let tube : Vec<bool> = segments.iter().map(|s| s.width() >= lod.thin).collect();
+    for chain in chains(&segments, &tube) {
+        let current_color_i : usize = segments[chain[0]].color_i as usize;
+        let width = chain.iter().map(|i| segments[*i].width()).fold(0.0, f64::max);
+        sweep(&mut meshes[current_color_i], &segments, &chain, lod.sides(width));
+    }
 
-            meshes[current_color_i].add_face(top[a_t], e1, top[b_t]);
-            meshes[current_color_i].add_face(bot[b_b], e2, bot[a_b]);
+    if lod.lines {
+        for (s, _) in segments.iter().zip(&tube).filter(|(_, tube)| !**tube) {
+            let mesh = &mut meshes[s.color_i as usize];
+            let zero = Vector3::new(0.0, 0.0, 0.0);
+            let v_b = s.length + (s.b().pos() - s.a().pos()).norm();
+            let a = mesh.add_vert_with(&s.a().pos(), &zero, [0.0, s.length]);
+            let b = mesh.add_vert_with(&s.b().pos(), &zero, [0.0, v_b]);
+            mesh.add_line(a, b);
         }
     }
 
@@ -471,13 +790,126 @@ pub fn gen_geometry(segments : Vec<Segment>, leaves : Vec<Leaf>,
         for v in l.pts {
             verts.push(meshes[current_color_i].add_vert(&v));
         }
+        meshes[current_color_i].planar_uvs(&verts);
         meshes[current_color_i].add_poly(verts);
     }
 
-    for obj in objects {
-        //add all objects to result mesh
-        meshes[0].merge(&obj.get_transformed_mesh());
+    //leaf normals
+    for m in &mut meshes {
+        m.fill_normals();
     }
 
     meshes
 }
+
+//copy the objects in the first mesh, for formats without instancing
+pub fn merge_objects(meshes : &mut [Mesh], objects : &Instances) {
+    for obj in objects.expand() {
+        //add all objects to result mesh
+        meshes[0].merge(&obj);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //unmerged segments of a string with the default parameters
+    fn segments(s : &str) -> Vec<Segment> {
+        interpret(read_input(s).unwrap(), &Params::default()).unwrap().0
+    }
+
+    fn branch_mesh(s : &str, lod : &Lod) -> Mesh {
+        gen_meshes(segments(s), Vec::new(), 1, lod).remove(0)
+    }
+
+    #[test]
+    fn read_text_or_json() {
+        let (_, leaves, _) = read_str("{.f.f.}", Params::default()).unwrap();
+        assert_eq!(leaves.len(), 1);
+        assert_eq!(leaves[0].pts.len(), 3);
+
+        let (segments, _, _) = read_str("{\"tropism\":null,\"objects\":{},\"rule_sets\":[\"root\"],\
+                                         \"modules\":[{\"sym\":\"F\",\"params\":[2.0],\"rule_set\":\"root\",\"object\":false}]}",
+                                        Params::default()).unwrap();
+        assert_eq!(segments.len(), 1);
+    }
+
+    #[test]
+    fn read_errors() {
+        let error = |s : &str| read_input(s).err().unwrap();
+        assert_eq!(error("F(1,x)"), "invalid parameter `x` of F");
+        assert_eq!(error("@0 1 a 0\nF"), "invalid tropism `a`");
+        assert_eq!(error("=angle\nF"), "expected a number after `angle`");
+        assert_eq!(error("=color 0 green\nF"), "expected a color as rrggbb, found `green`");
+        assert!(error("#leaf missing.obj\nF").starts_with("could not read mesh missing.obj"));
+        assert!(error("{\"modules\": 1}").starts_with("invalid JSON input"));
+
+        let params = Params{shrink: Some(2.0), ..Params::default()};
+        assert!(interpret(read_input("F").unwrap(), &params).is_err());
+    }
+
+    #[test]
+    fn tube_rings() {
+        //two rings of sides + 1 vertices and two cap vertices
+        let m = branch_mesh("F", &Lod::new(6));
+        assert_eq!(m.get_verts().len(), 2 * 7 + 2);
+        assert_eq!(m.get_triangles().len() / 3, 2 * 6 + 2 * 6);
+
+        //the joint between collinear segments is a single ring
+        let m = branch_mesh("FF", &Lod::new(6));
+        assert_eq!(m.get_verts().len(), 3 * 7 + 2);
+        let m = branch_mesh("F+F", &Lod::new(5));
+        assert_eq!(m.get_verts().len(), 3 * 6 + 2);
+
+        //a branch is a tube of its own, the trunk goes on through the branch point
+        let m = branch_mesh("F[+F]F", &Lod::new(6));
+        assert_eq!(m.get_verts().len(), (3 * 7 + 2) + (2 * 7 + 2));
+    }
+
+    #[test]
+    fn lod_sides() {
+        let lod = Lod{sides: (3, 12), widths: (0.1, 0.5), thin: 0.0, lines: false};
+        assert_eq!(lod.sides(0.1), 3);
+        assert_eq!(lod.sides(0.4), 10);
+        assert_eq!(lod.sides(0.5), 12);
+        //clamped outside of the widths
+        assert_eq!(lod.sides(0.01), 3);
+        assert_eq!(lod.sides(2.0), 12);
+        assert_eq!(Lod::new(6).sides(0.3), 6);
+    }
+
+    #[test]
+    fn thin_branches() {
+        //the second segment is the thinnest one, 0.1 wide
+        let mut lod = Lod{sides: (6, 6), widths: (0.1, 0.5), thin: 0.2, lines: false};
+        let m = branch_mesh("F!(0)F", &lod);
+        assert_eq!(m.get_verts().len(), 2 * 7 + 2);
+        assert!(m.get_lines().is_empty());
+
+        lod.lines = true;
+        let m = branch_mesh("F!(0)F", &lod);
+        assert_eq!(m.get_verts().len(), 2 * 7 + 2 + 2);
+        assert_eq!(m.get_lines().len(), 2);
+        assert_eq!(m.get_triangles().len() / 3, 2 * 6 + 2 * 6);
+    }
+
+    #[test]
+    fn merge_segments() {
+        let (merged, removed) = process_segments(segments("FFF"));
+        assert_eq!((merged.len(), removed), (1, 2));
+        assert!((merged[0].b().pos() - Vector3::new(0.0, 0.0, 3.0)).norm() < 1e-9);
+
+        //the branch point is kept, the trunk after it is merged
+        let (merged, removed) = process_segments(segments("F[+F]F"));
+        assert_eq!((merged.len(), removed), (3, 0));
+        let (merged, removed) = process_segments(segments("F[+F]FF"));
+        assert_eq!((merged.len(), removed), (3, 1));
+        assert_eq!(merged[2].parent, Some(0));
+
+        //moves without drawing, width and color changes break the chains
+        for s in ["FfF", "F!(0.5)F", "F'F"] {
+            assert_eq!(process_segments(segments(s)).1, 0, "{}", s);
+        }
+    }
+}
@@ -0,0 +1,453 @@
+//glTF 2.0 binary (.glb) export.
+//The scene has one node for the branches, one for the leaves and one per object instance,
+//the instances of an object all reference the same mesh.
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use serde_json::{json, Value};
+use crate::mesh::{Mesh, Shading};
+use crate::object::Instances;
+use crate::vector3::Vector3;
+
+const ARRAY_BUFFER : u32 = 34962;
+const ELEMENT_ARRAY_BUFFER : u32 = 34963;
+const UNSIGNED_INT : u32 = 5125;
+const FLOAT : u32 = 5126;
+const LINES : u32 = 1;
+const TRIANGLES : u32 = 4;
+
+//json arrays and binary buffer being built
+struct Gltf {
+    buffer : Vec<u8>,
+    views : Vec<Value>,
+    accessors : Vec<Value>,
+    materials : Vec<Value>,
+    material_ids : HashMap<String, usize>,
+    meshes : Vec<Value>,
+    nodes : Vec<Value>,
+    shading : Shading,
+}
+
+impl Gltf {
+    fn new(palette : &[(f64, f64, f64)], shading : Shading) -> Gltf {
+        let mut res = Gltf{buffer: Vec::new(), views: Vec::new(), accessors: Vec::new(),
+            materials: Vec::new(), material_ids: HashMap::new(), meshes: Vec::new(), nodes: Vec::new(),
+            shading};
+        for (i, (r, g, b)) in palette.iter().enumerate() {
+            res.material(&format!("color{}", i), (r / 255.0, g / 255.0, b / 255.0));
+        }
+
+        res
+    }
+
+    //index of the material, added with the color if the name is new
+    fn material(&mut self, name : &str, (r, g, b) : (f64, f64, f64)) -> usize {
+        if let Some(i) = self.material_ids.get(name) {
+            return *i;
+        }
+
+        self.materials.push(json!({
+            "name": name,
+            "pbrMetallicRoughness": {
+                "baseColorFactor": [r, g, b, 1.0],
+                "metallicFactor": 0.0,
+                "roughnessFactor": 1.0
+            },
+            "doubleSided": true
+        }));
+        self.material_ids.insert(name.to_string(), self.materials.len() - 1);
+        self.materials.len() - 1
+    }
+
+    //buffer view on bytes, target tells if they are vertex attributes or indices
+    fn view(&mut self, bytes : &[u8], target : u32) -> usize {
+        let offset = self.buffer.len();
+        self.buffer.extend_from_slice(bytes);
+        self.views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": bytes.len(),
+            "target": target
+        }));
+
+        self.views.len() - 1
+    }
+
+    //accessor on n-component float vectors, stored in a buffer view of their own
+    fn accessor(&mut self, values : &[f32], n : usize, bounds : bool) -> usize {
+        let bytes : Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let view = self.view(&bytes, ARRAY_BUFFER);
+
+        let kind = ["SCALAR", "VEC2", "VEC3", "VEC4"][n - 1];
+        let mut accessor = json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": values.len() / n,
+            "type": kind
+        });
+        //required for positions
+        if bounds {
+            let mut min = vec![f32::INFINITY; n];
+            let mut max = vec![f32::NEG_INFINITY; n];
+            for v in values.chunks(n) {
+                for k in 0..n {
+                    min[k] = min[k].min(v[k]);
+                    max[k] = max[k].max(v[k]);
+                }
+            }
+            accessor["min"] = json!(min);
+            accessor["max"] = json!(max);
+        }
+        self.accessors.push(accessor);
+
+        self.accessors.len() - 1
+    }
+
+    //accessor on vertex indices, stored in a buffer view of their own
+    fn index_accessor(&mut self, indices : &[u32]) -> usize {
+        let bytes : Vec<u8> = indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+        let view = self.view(&bytes, ELEMENT_ARRAY_BUFFER);
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": UNSIGNED_INT,
+            "count": indices.len(),
+            "type": "SCALAR"
+        }));
+
+        self.accessors.len() - 1
+    }
+
+    //triangles of a material. With smooth shading, the triangles share their vertices
+    //through indices. With flat shading, vertices are duplicated so that each triangle
+    //can have its own normal
+    fn primitive(&mut self, verts : &[Vector3], vert_normals : &[Vector3], vert_uvs : &[[f64; 2]],
+                 triangles : &[[usize; 3]], material : usize) -> Value {
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut indices = Vec::new();
+        let mut local : HashMap<usize, u32> = HashMap::new();//vertex -> index in the primitive
+        for t in triangles {
+            let (a, b, c) = (verts[t[0]], verts[t[1]], verts[t[2]]);
+            let n = (b - a).cross(c - a);
+            let n = if n.norm() > 0.0 {n.normalized()} else {Vector3::new(0.0, 0.0, 1.0)};
+            for (v, i) in [(a, t[0]), (b, t[1]), (c, t[2])] {
+                if self.shading == Shading::Smooth {
+                    let next = local.len() as u32;
+                    let k = *local.entry(i).or_insert(next);
+                    indices.push(k);
+                    if k < next {
+                        continue;//already written
+                    }
+                }
+                //vertices without normal take the one of their first triangle
+                let vn = match self.shading {
+                    Shading::Smooth if vert_normals[i].norm() > 0.0 => vert_normals[i],
+                    _ => n
+                };
+                positions.extend(v.to_arr().iter().map(|x| *x as f32));
+                normals.extend(vn.to_arr().iter().map(|x| *x as f32));
+                //glTF puts the origin of textures at the top left
+                uvs.extend([vert_uvs[i][0] as f32, 1.0 - vert_uvs[i][1] as f32]);
+            }
+        }
+
+        let mut primitive = json!({
+            "attributes": {
+                "POSITION": self.accessor(&positions, 3, true),
+                "NORMAL": self.accessor(&normals, 3, false),
+                "TEXCOORD_0": self.accessor(&uvs, 2, false)
+            },
+            "material": material,
+            "mode": TRIANGLES
+        });
+        if self.shading == Shading::Smooth {
+            primitive["indices"] = json!(self.index_accessor(&indices));
+        }
+
+        primitive
+    }
+
+    fn line_primitive(&mut self, verts : &[Vector3], vert_uvs : &[[f64; 2]],
+                      lines : &[[usize; 2]], material : usize) -> Value {
+        let mut positions = Vec::new();
+        let mut uvs = Vec::new();
+        for i in lines.iter().flatten() {
+            positions.extend(verts[*i].to_arr().iter().map(|x| *x as f32));
+            uvs.extend([vert_uvs[*i][0] as f32, 1.0 - vert_uvs[*i][1] as f32]);
+        }
+
+        json!({
+            "attributes": {
+                "POSITION": self.accessor(&positions, 3, true),
+                "TEXCOORD_0": self.accessor(&uvs, 2, false)
+            },
+            "material": material,
+            "mode": LINES
+        })
+    }
+
+    //index of a new mesh, None if there is nothing to draw
+    fn mesh(&mut self, name : &str, verts : &[Vector3], normals : &[Vector3], uvs : &[[f64; 2]],
+            groups : Vec<(usize, Vec<[usize; 3]>)>, lines : Vec<(usize, Vec<[usize; 2]>)>) -> Option<usize> {
+        let mut primitives : Vec<Value> = groups.iter()
+            .filter(|(_, triangles)| !triangles.is_empty())
+            .map(|(material, triangles)| self.primitive(verts, normals, uvs, triangles, *material))
+            .collect();
+        for (material, lines) in lines.iter().filter(|(_, lines)| !lines.is_empty()) {
+            let p = self.line_primitive(verts, uvs, lines, *material);
+            primitives.push(p);
+        }
+        if primitives.is_empty() {
+            return None;
+        }
+
+        self.meshes.push(json!({"name": name, "primitives": primitives}));
+        Some(self.meshes.len() - 1)
+    }
+
+    //leaf faces grouped by material, faces without material of their own use color.
+    //Triangles are added to the groups in groups, a material has a single group.
+    //Fails if a material library of the mesh can not be read
+    fn leaf_groups(&mut self, mesh : &Mesh, color : usize,
+                   groups : &mut BTreeMap<usize, Vec<[usize; 3]>>, offset : usize) -> Result<(), String> {
+        let colors = mtl_colors(mesh.get_mtllibs())?;
+        let mut material = color;
+        let mut switches = mesh.get_materials().iter().peekable();
+        for (i, f) in mesh.get_leaf_faces().iter().enumerate() {
+            while let Some((_, m)) = switches.next_if(|(start, _)| *start == i) {
+                material = match m {
+                    Some(name) => {
+                        let kd = colors.get(name).copied().unwrap_or((1.0, 1.0, 1.0));
+                        self.material(name, kd)
+                    },
+                    None => color
+                };
+            }
+            //fan triangulation, faces are convex
+            let group = groups.entry(material).or_default();
+            for k in 1..f.len().saturating_sub(1) {
+                group.push([f[0] + offset, f[k] + offset, f[k + 1] + offset]);
+            }
+        }
+        Ok(())
+    }
+
+    fn node(&mut self, node : Value) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+}
+
+//diffuse colors of the materials in .mtl files, in [0, 1]
+fn mtl_colors(mtllibs : &[String]) -> Result<HashMap<String, (f64, f64, f64)>, String> {
+    let mut res = HashMap::new();
+    for lib in mtllibs {
+        let s = fs::read_to_string(lib)
+            .map_err(|e| format!("could not read material library {}: {}", lib, e))?;
+
+        let mut current = None;
+        for l in s.lines() {
+            let split : Vec<&str> = l.split_whitespace().collect();
+            match split.as_slice() {
+                ["newmtl", name] => current = Some(name.to_string()),
+                ["Kd", r, g, b] => {
+                    if let (Some(name), Ok(r), Ok(g), Ok(b)) = (&current, r.parse(), g.parse(), b.parse()) {
+                        res.insert(name.clone(), (r, g, b));
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+//meshes are indexed with their color index, as made by engine::gen_meshes.
+//The whole scene is scaled by scale
+pub fn glb(meshes : &[Mesh], objects : &Instances, palette : &[(f64, f64, f64)], scale : f64,
+           shading : Shading) -> Result<Vec<u8>, String> {
+    let mut gltf = Gltf::new(palette, shading);
+    let mut children = Vec::new();
+
+    //branches are triangles and leaves are polygons, one primitive per color
+    let mut verts = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut branches = Vec::new();
+    let mut lines = Vec::new();
+    let mut leaves = BTreeMap::new();
+    for (i, m) in meshes.iter().enumerate() {
+        let offset = verts.len();
+        verts.extend(m.get_verts().iter().copied());
+        normals.extend(m.get_normals().iter().copied());
+        uvs.extend(m.get_uvs().iter().copied());
+        let triangles = m.get_triangles().chunks(3)
+            .map(|t| [t[0] + offset, t[1] + offset, t[2] + offset])
+            .collect();
+        branches.push((i, triangles));
+        lines.push((i, m.get_lines().chunks(2).map(|l| [l[0] + offset, l[1] + offset]).collect()));
+        gltf.leaf_groups(m, i, &mut leaves, offset)?;
+    }
+    if let Some(mesh) = gltf.mesh("branches", &verts, &normals, &uvs, branches, lines) {
+        children.push(gltf.node(json!({"name": "branches", "mesh": mesh})));
+    }
+    if let Some(mesh) = gltf.mesh("leaves", &verts, &normals, &uvs, leaves.into_iter().collect(), Vec::new()) {
+        children.push(gltf.node(json!({"name": "leaves", "mesh": mesh})));
+    }
+
+    //one mesh per alias, written when first placed
+    let mut object_meshes : HashMap<&str, Option<usize>> = HashMap::new();
+    for (i, obj) in objects.objects.iter().enumerate() {
+        let mesh = match object_meshes.get(obj.get_name()) {
+            Some(mesh) => *mesh,
+            None => {
+                let shared = objects.mesh(obj);
+                let mut groups = BTreeMap::new();
+                gltf.leaf_groups(shared, 0, &mut groups, 0)?;
+                let mesh = gltf.mesh(obj.get_name(), shared.get_verts(), shared.get_normals(),
+                                     shared.get_uvs(), groups.into_iter().collect(), Vec::new());
+                object_meshes.insert(obj.get_name(), mesh);
+                mesh
+            }
+        };
+        if let Some(mesh) = mesh {
+            children.push(gltf.node(json!({
+                "name": format!("{}.{}", obj.get_name(), i),
+                "mesh": mesh,
+                "matrix": obj.get_transform().to_cols()
+            })));
+        }
+    }
+
+    let root = gltf.node(json!({"name": "plant", "children": children, "scale": [scale, scale, scale]}));
+
+    let mut json = json!({
+        "asset": {"version": "2.0", "generator": "graph3d"},
+        "scene": 0,
+        "scenes": [{"nodes": [root]}],
+        "nodes": gltf.nodes,
+        "materials": gltf.materials,
+        "buffers": [{"byteLength": gltf.buffer.len()}]
+    });
+    //empty arrays are not allowed
+    if !gltf.meshes.is_empty() {
+        json["meshes"] = json!(gltf.meshes);
+        json["bufferViews"] = json!(gltf.views);
+        json["accessors"] = json!(gltf.accessors);
+    } else {
+        json.as_object_mut().unwrap().remove("buffers");
+    }
+
+    let mut json = serde_json::to_vec(&json).expect("Could not serialize glTF.");
+    let mut bin = gltf.buffer;
+    while !json.len().is_multiple_of(4) {
+        json.push(b' ');
+    }
+    while !bin.len().is_multiple_of(4) {
+        bin.push(0);
+    }
+
+    //header, JSON chunk and binary chunk
+    let mut res = Vec::new();
+    let length = 12 + 8 + json.len() + if bin.is_empty() {0} else {8 + bin.len()};
+    res.extend_from_slice(b"glTF");
+    res.extend_from_slice(&2u32.to_le_bytes());
+    res.extend_from_slice(&(length as u32).to_le_bytes());
+    res.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    res.extend_from_slice(b"JSON");
+    res.extend_from_slice(&json);
+    if !bin.is_empty() {
+        res.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+        res.extend_from_slice(b"BIN\0");
+        res.extend_from_slice(&bin);
+    }
+
+    Ok(res)
+}
+
+pub fn write_glb(meshes : &[Mesh], objects : &Instances, palette : &[(f64, f64, f64)],
+                 scale : f64, shading : Shading, path : &str) -> Result<(), String> {
+    let res = glb(meshes, objects, palette, scale, shading)?;
+    fs::write(path, res).map_err(|e| format!("could not write {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(b : &[u8], i : usize) -> usize {
+        u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]) as usize
+    }
+
+    #[test]
+    fn one_primitive_per_material() {
+        let path = std::env::temp_dir().join("graph3d_usemtl.obj");
+        fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+                          usemtl a\nf 1 2 3\nusemtl b\nf 1 2 3\nusemtl a\nf 1 2 3\nusemtl a\nf 1 2 3\n").unwrap();
+        let mesh = Mesh::load(&path.to_string_lossy().to_string()).unwrap();
+
+        let mut gltf = Gltf::new(&[(0.0, 0.0, 0.0)], Shading::Flat);
+        let mut groups = BTreeMap::new();
+        gltf.leaf_groups(&mesh, 0, &mut groups, 0).unwrap();
+        let sizes : Vec<(usize, usize)> = groups.iter().map(|(m, t)| (*m, t.len())).collect();
+
+        //color0, then a and b in order of appearance
+        assert_eq!(sizes, vec![(1, 3), (2, 1)]);
+    }
+
+    #[test]
+    fn glb_layout() {
+        let mut m = Mesh::new();
+        let v : Vec<usize> = [(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (1.0, 1.0, 0.0), (0.0, 1.0, 0.0)].iter()
+            .map(|(x, y, z)| m.add_vert(&Vector3::new(*x, *y, *z)))
+            .collect();
+        m.add_face(v[0], v[1], v[2]);
+        m.add_line(v[2], v[3]);
+        m.add_poly(v.clone());
+
+        let res = glb(&[m.clone()], &Instances::default(), &[(90.0, 35.0, 35.0)], 2.0, Shading::Smooth).unwrap();
+
+        //header, JSON chunk and binary chunk
+        assert_eq!(&res[0..4], b"glTF");
+        assert_eq!(u32_at(&res, 4), 2);
+        assert_eq!(u32_at(&res, 8), res.len());
+        let json_len = u32_at(&res, 12);
+        assert_eq!(&res[16..20], b"JSON");
+        assert_eq!(json_len % 4, 0);
+        let bin = 20 + json_len;
+        let bin_len = u32_at(&res, bin);
+        assert_eq!(&res[bin + 4..bin + 8], b"BIN\0");
+        assert_eq!(bin + 8 + bin_len, res.len());
+
+        let json : Value = serde_json::from_slice(&res[20..bin]).unwrap();
+        assert_eq!(json["buffers"][0]["byteLength"].as_u64().unwrap() as usize, bin_len);
+        let count = |mesh : usize, primitive : usize, attribute : &str| {
+            let a = json["meshes"][mesh]["primitives"][primitive]["attributes"][attribute].as_u64().unwrap();
+            json["accessors"][a as usize]["count"].as_u64().unwrap()
+        };
+        let indices = |mesh : usize, primitive : usize| {
+            let a = json["meshes"][mesh]["primitives"][primitive]["indices"].as_u64().unwrap();
+            assert_eq!(json["bufferViews"][json["accessors"][a as usize]["bufferView"].as_u64().unwrap() as usize]
+                           ["target"], 34963);
+            json["accessors"][a as usize]["count"].as_u64().unwrap()
+        };
+        //one triangle and one line for the branches, two triangles sharing two vertices for the leaf
+        assert_eq!(count(0, 0, "POSITION"), 3);
+        assert_eq!(count(0, 0, "NORMAL"), 3);
+        assert_eq!(indices(0, 0), 3);
+        assert_eq!(count(0, 1, "POSITION"), 2);
+        assert!(json["meshes"][0]["primitives"][1]["attributes"].get("NORMAL").is_none());
+        assert_eq!(count(1, 0, "POSITION"), 4);
+        assert_eq!(count(1, 0, "TEXCOORD_0"), 4);
+        assert_eq!(indices(1, 0), 6);
+
+        //flat shading duplicates the vertices
+        let res = glb(&[m], &Instances::default(), &[(90.0, 35.0, 35.0)], 2.0, Shading::Flat).unwrap();
+        let bin = 20 + u32_at(&res, 12);
+        let json : Value = serde_json::from_slice(&res[20..bin]).unwrap();
+        let leaf = &json["meshes"][1]["primitives"][0];
+        assert!(leaf.get("indices").is_none());
+        assert_eq!(json["accessors"][leaf["attributes"]["POSITION"].as_u64().unwrap() as usize]["count"], 6);
+    }
+}
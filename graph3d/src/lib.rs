@@ -0,0 +1,15 @@
+//Turtle interpretation of L-system strings and OBJ geometry generation.
+//engine::read_str builds segments from a string, engine::gen_geometry turns them into meshes,
+//written as OBJ by mesh or as glTF by gltf.
+
+pub mod engine;
+pub mod matrix4;
+pub mod mesh;
+pub mod turtle;
+pub mod vector3;
+pub mod object;
+pub mod gltf;
+pub mod topology;
+pub mod cli;
+
+pub use mesh::Mesh;
@@ -1,100 +1,76 @@
-extern crate core;
+use std::fs;
+use std::process;
+use clap::Parser;
+use graph3d::{engine, gltf, mesh};
+use graph3d::cli::{Format, RenderArgs};
+use graph3d::topology::Topology;
 
-use std::f64::consts::PI;
-use std::{env, fs};
-use std::fs::File;
-use std::error::Error;
-use std::io::Write;
-use std::convert::TryInto;
+/// Generate .obj meshes from the output of plants
+#[derive(Parser)]
+#[command(name = "graph3d")]
+struct Args {
+    /// Output of plants, as text or JSON
+    input: String,
 
-mod engine;
-mod matrix4;
-mod mesh;
-mod turtle;
-mod vector3;
-mod object;
+    /// Name of the output files, OUTPUT.obj and its material library OUTPUT.mtl, or OUTPUT.glb
+    output: String,
 
+    #[command(flatten)]
+    render: RenderArgs,
+}
 
 fn main() {
-    // Parse arguments
-    let mut args = env::args();
-    args.next();
-    let input = args.next()
-        .expect("usage: ./graph3d input output [angle] [dist] [reason] [min_d] [max_d]")
-        .clone();
-    println!("{}", input);
-    let output = args.next()
-        .expect("Not enough arguments.")
-        .clone();
-    let angle = args.next()
-        .unwrap_or(String::from("22.5"))
-        .parse::<f64>()
-        .expect("Invalid value for angle");
-    let dist = args.next()
-        .unwrap_or(String::from("1.0"))
-        .parse::<f64>()
-        .expect("Invalid value for distance.");
-    let reason_d = args.next()
-        .unwrap_or(String::from("0.8"))
-        .parse::<f64>().expect("Invalid value for reason");
-    let min_d = args.next()
-        .unwrap_or(String::from("0.1"))
-        .parse::<f64>().expect("Invalid value for min_d.");
-    let max_d = args.next()
-        .unwrap_or(String::from("0.5"))
-        .parse::<f64>().expect("Invalid value for max_d.");
+    if let Err(e) = run(Args::parse()) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
 
     // Read file and get string
-    let in_str = fs::read_to_string(input)
-        .expect("Failed reading file.");
+    let in_str = fs::read_to_string(&args.input)
+        .map_err(|e| format!("could not read {}: {}", args.input, e))?;
 
-    // Available colors
-    let mut colors = Vec::new();
-    colors.push((90.0, 35.0, 35.0));    // 5a2323, brown
-    colors.push((118.0, 156.0, 35.0));  // 769c23, green
-    colors.push((118.0, 156.0, 35.0));//third color just to add more possibilities
+    let params = args.render.params()?;
 
     // Generate segments
-    let nb_colors : i64 = (&colors).len().try_into().unwrap();
+    let mut input = engine::read_input(&in_str)?;
+    let params = params.or(std::mem::take(&mut input.params));
+    let palette = params.palette();
+    let nb_colors = palette.len() as i64;
     let (segments,
         leaves,
-        objects) = engine::read_str(&in_str,
-                                      dist, angle * (PI / 180.0), (min_d, max_d),
-                                              reason_d,
-                                              nb_colors);
+        objects) = engine::interpret(input, &params)?;
+    let (segments, removed) = engine::process_segments(segments);
+    println!("Merged segments: {} removed", removed);
+    if let Some(path) = &args.render.topology {
+        Topology::new(&segments).write(path)?;
+        println!("Successfully wrote to {}", path);
+    }
 
     // Generate & print geometry
-    let meshes = engine::gen_geometry(segments, leaves, objects, nb_colors);  // Meshes are indexed with their color index
-
-    for i in 0..nb_colors {  // nb_colors == nb_meshes
-        let j : usize = i as usize;
-
-        // Create .obj text
-        let out_str = meshes[j].clone().get_str();
-
-        // Open .obj file
-        let tmp_output = output.clone() + &j.to_string() + ".obj";
-        let mut file = match File::create(&tmp_output) {
-            Err(why) => panic!("Couldn't create {}: {}", output, why.description()),
-            Ok(file) => file,
-        };
-
-        // Write .obj file
-        match file.write_all(out_str.as_bytes()) {
-            Err(why) => panic!("Couldn't write to {}: {}", tmp_output, why.description()),
-            Ok(_) => println!("Successfully wrote to {}", tmp_output),
-        };
+    let format = args.render.format;
+    let output = args.output.trim_end_matches(&format!(".{}", format.extension()));
+    // Meshes are indexed with their color index
+    let lod = args.render.lod(params.widths());
+    let mut meshes = engine::gen_meshes(segments, leaves, nb_colors, &lod);
+    match format {
+        Format::Obj => {
+            engine::merge_objects(&mut meshes, &objects);
+            let mtl = format!("{}.mtl", output);
+            let path = format!("{}.obj", output);
+            mesh::write_mtl(&palette, &mtl)?;
+            println!("Successfully wrote to {}", mtl);
+            mesh::write_obj(&meshes, &path, &mtl, args.render.shading)?;
+            println!("Successfully wrote to {}", path);
+        },
+        Format::Glb => {
+            let path = format!("{}.glb", output);
+            gltf::write_glb(&meshes, &objects, &palette, 1.0, args.render.shading, &path)?;
+            println!("Successfully wrote to {}", path);
+        }
     }
 
-    // // Open final .mtl file
-    // let mut file = match File::create(&output) {
-    //     Err(why) => panic!("Couldn't create {}: {}", output, why.description()),
-    //     Ok(file) => file,
-    // };
-
-    // // Write final .mtl file
-    // match file.write_all(out_str.as_bytes()) {
-    //     Err(why) => panic!("Couldn't write to {}: {}", output, why.description()),
-    //     Ok(_) => println!("Successfully wrote to {}", output),
-    // };
+    Ok(())
 }
@@ -2,22 +2,22 @@ use crate::turtle;
 use crate::vector3;
 
 #[derive(Debug)]
-#[derive(Clone)]
+#[derive(Copy, Clone)]
 pub struct Matrix4 {
     // Columns
-    j0: Vec<f64>,
-    j1: Vec<f64>,
-    j2: Vec<f64>,
-    j3: Vec<f64>
+    j0: [f64; 4],
+    j1: [f64; 4],
+    j2: [f64; 4],
+    j3: [f64; 4]
 }
 
 impl Matrix4 {
     pub fn new() -> Matrix4 {
         Matrix4{
-            j0: vec![0.0; 4],
-            j1: vec![0.0; 4],
-            j2: vec![0.0; 4],
-            j3: vec![0.0; 4],
+            j0: [0.0; 4],
+            j1: [0.0; 4],
+            j2: [0.0; 4],
+            j3: [0.0; 4],
         }
     }
 
@@ -43,6 +43,16 @@ impl Matrix4 {
         res
     }
 
+    //the 16 coefficients in column-major order
+    pub fn to_cols(&self) -> Vec<f64> {
+        [self.j0, self.j1, self.j2, self.j3].concat()
+    }
+
+    //transform a direction, without the translation
+    pub fn mult_dir(&self, v: vector3::Vector3) -> vector3::Vector3 {
+        self.mult(v) - self.mult(vector3::Vector3::new(0.0, 0.0, 0.0))
+    }
+
     pub fn mult(&self, v: vector3::Vector3) -> vector3::Vector3 {
         let x =   self.j0[0] * *v.x()
                 + self.j1[0] * *v.y()
@@ -1,33 +1,64 @@
 use crate::vector3;
+use crate::vector3::Vector3;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 
+//how normals are written
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Shading {
+    Flat,//one normal per face
+    Smooth//vertex normals
+}
+
+impl Shading {
+    pub fn from(s : &str) -> Option<Shading> {
+        match s {
+            "flat" => Some(Shading::Flat),
+            "smooth" => Some(Shading::Smooth),
+            _ => None
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Mesh {
     verts : Vec<vector3::Vector3>,
+    normals : Vec<vector3::Vector3>,//one per vertex, zero until known
+    uvs : Vec<[f64; 2]>,//texture coordinates, one per vertex
     triangles : Vec<usize>,
+    lines : Vec<usize>,//pairs of vertices
     leaf_faces : Vec<Vec<usize>>,
+    mtllibs : Vec<String>,//material libraries of imported objects
+    //material used from a leaf face index, None for the material of the mesh
+    materials : Vec<(usize, Option<String>)>,
 }
 
 impl Mesh {
     pub fn new() -> Mesh {
-        Mesh{verts: Vec::new(), triangles: Vec::new(),
-            leaf_faces: Vec::new()}
+        Mesh{verts: Vec::new(), normals: Vec::new(), uvs: Vec::new(), triangles: Vec::new(), lines: Vec::new(),
+            leaf_faces: Vec::new(), mtllibs: Vec::new(), materials: Vec::new()}
     }
 
-    pub fn load(path: &String) -> Mesh {
-        let in_str = match fs::read_to_string(path) {
-            Ok(s) => s,
-            _ => {
-                println!("Could not load mesh at: {}", path);
-                return Mesh::new();
-            }
-        };
+    //read an .obj file, faces are leaf faces. Fails on the first invalid line
+    pub fn load(path: &String) -> Result<Mesh, String> {
+        let in_str = fs::read_to_string(path)
+            .map_err(|e| format!("could not read mesh {}: {}", path, e))?;
 
         let lines = in_str.lines();
 
         let mut result = Mesh::new();
-        for l in lines {
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        //vertex used with each texture coordinate and normal,
+        //vertices are duplicated if they are used with several
+        let mut corners : HashMap<(usize, Option<usize>, Option<usize>), usize> = HashMap::new();
+        let mut used = HashSet::new();
+        for (n, l) in lines.enumerate() {
+            let invalid = |what : &str| format!("{}: line {}: invalid {} `{}`", path, n + 1, what, l);
             let mut split = l.split(" ");
             let line_type = match split.next() {
                 Some(s) => s,
@@ -36,36 +67,94 @@ impl Mesh {
 
             match line_type {
                 "v" => {
-                    let x = split.next().unwrap_or("0.0")
-                        .parse::<f64>()
-                        .expect("Invalid vertex data");
-                    let y = split.next().unwrap_or("0.0")
-                        .parse::<f64>()
-                        .expect("Invalid vertex data");
-                    let z = split.next().unwrap_or("0.0")
-                        .parse::<f64>()
-                        .expect("Invalid vertex data");
+                    let mut coord = || split.next().unwrap_or("0.0").parse::<f64>()
+                        .map_err(|_| invalid("vertex"));
+                    let (x, y, z) = (coord()?, coord()?, coord()?);
                     result.add_vert(&vector3::Vector3::new(x, y, z));
                 },
+                "vn" => {
+                    let n = split.map(|x| x.parse::<f64>())
+                        .collect::<Result<Vec<f64>, _>>()
+                        .ok()
+                        .filter(|n| n.len() == 3)
+                        .ok_or_else(|| invalid("normal"))?;
+                    normals.push(vector3::Vector3::new(n[0], n[1], n[2]));
+                },
+                "vt" => {
+                    let mut coord = || split.next().unwrap_or("0.0").parse::<f64>()
+                        .map_err(|_| invalid("texture coordinates"));
+                    let (u, v) = (coord()?, coord()?);
+                    uvs.push([u, v]);
+                },
                 "f" => {
+                    //1-based index below len
+                    let index = |i : &str, len : usize| i.parse::<usize>().ok()
+                        .filter(|i| *i >= 1 && *i <= len)
+                        .map(|i| i - 1)
+                        .ok_or_else(|| invalid("face"));
                     let mut face = Vec::new();
                     for v_id in split {
                         let mut id_split = v_id.split("/");
-                        let v_id = id_split.nth(0).expect("No face data.");
-                        let v_id = v_id.parse::<usize>().expect("Invalid face data.") - 1;
+                        let v_id = index(id_split.next().unwrap_or(""), result.verts.len())?;
+                        let t_id = match id_split.next().filter(|i| !i.is_empty()) {
+                            Some(t) => Some(index(t, uvs.len())?),
+                            None => None
+                        };
+                        let n_id = match id_split.next().filter(|i| !i.is_empty()) {
+                            Some(n) => Some(index(n, normals.len())?),
+                            None => None
+                        };
+                        let v_id = match corners.get(&(v_id, t_id, n_id)) {
+                            Some(v) => *v,
+                            None => {
+                                let v = if used.insert(v_id) {
+                                    v_id
+                                } else {
+                                    result.add_vert(&result.verts[v_id].clone())
+                                };
+                                if let Some(n_id) = n_id {
+                                    result.normals[v] = normals[n_id];
+                                }
+                                if let Some(t_id) = t_id {
+                                    result.uvs[v] = uvs[t_id];
+                                }
+                                corners.insert((v_id, t_id, n_id), v);
+                                v
+                            }
+                        };
                         face.push(v_id);
                     }
                     result.leaf_faces.push(face);
                 },
+                //libraries are relative to the .obj file
+                "mtllib" => {
+                    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+                    for lib in split {
+                        result.mtllibs.push(dir.join(lib).to_string_lossy().to_string());
+                    }
+                },
+                "usemtl" => {
+                    if let Some(m) = split.next() {
+                        result.materials.push((result.leaf_faces.len(), Some(m.to_string())));
+                    }
+                },
                 _ => {continue;}
             }
         }
-        result
+        result.fill_normals();
+        Ok(result)
     }
 
     pub fn add_vert(&mut self, p : &vector3::Vector3) -> usize {
+        self.add_vert_with(p, &vector3::Vector3::new(0.0, 0.0, 0.0), [0.0, 0.0])
+    }
+
+    //vertex with its normal and texture coordinates
+    pub fn add_vert_with(&mut self, p : &vector3::Vector3, n : &vector3::Vector3, uv : [f64; 2]) -> usize {
         let len = self.verts.len();
-        self.verts.push(p.clone());
+        self.verts.push(*p);
+        self.normals.push(*n);
+        self.uvs.push(uv);
 
         len
     }
@@ -76,6 +165,11 @@ impl Mesh {
         self.triangles.push(c);
     }
 
+    pub fn add_line(&mut self, a : usize, b : usize) {
+        self.lines.push(a);
+        self.lines.push(b);
+    }
+
     pub fn add_poly(&mut self, f : Vec<usize>) {
             self.leaf_faces.push(f.clone());
     }
@@ -88,6 +182,26 @@ impl Mesh {
         for v in &other.verts {
             self.verts.push(v.clone());
         }
+        self.normals.extend(other.normals.iter().copied());
+        self.uvs.extend(other.uvs.iter().copied());
+
+        //faces of other without material of their own go back to the one of the mesh
+        let face_offset = self.leaf_faces.len();
+        let foreign = matches!(self.materials.last(), Some((_, Some(_))));
+        if foreign && other.materials.first().is_none_or(|m| m.0 > 0) {
+            self.materials.push((face_offset, None));
+        }
+        for (start, m) in &other.materials {
+            self.materials.push((start + face_offset, m.clone()));
+        }
+        for lib in &other.mtllibs {
+            if !self.mtllibs.contains(lib) {
+                self.mtllibs.push(lib.clone());
+            }
+        }
+
+        self.triangles.extend(other.triangles.iter().map(|x| x + offset));
+        self.lines.extend(other.lines.iter().map(|x| x + offset));
 
         //add faces with index offset (use leaf_faces to support n-gons)
         for f in &other.leaf_faces {
@@ -95,49 +209,213 @@ impl Mesh {
         }
     }
 
-    pub fn get_str(self) -> String {
+    //normal of a polygon, Newell's method, the length is twice the area
+    pub fn face_normal(&self, f : &[usize]) -> Vector3 {
+        let mut n = Vector3::new(0.0, 0.0, 0.0);
+        for (i, a) in f.iter().enumerate() {
+            let b = f[(i + 1) % f.len()];
+            n = n + self.verts[*a].cross(self.verts[b]);
+        }
+
+        n
+    }
+
+    //set the unknown vertex normals to the area weighted average of their faces
+    pub fn fill_normals(&mut self) {
+        let mut sums = vec![Vector3::new(0.0, 0.0, 0.0); self.verts.len()];
+        for f in self.triangles.chunks(3).chain(self.leaf_faces.iter().map(|f| f.as_slice())) {
+            let n = self.face_normal(f);
+            for v in f {
+                sums[*v] = sums[*v] + n;
+            }
+        }
+
+        for (n, sum) in self.normals.iter_mut().zip(sums) {
+            if n.norm() == 0.0 && sum.norm() > 0.0 {
+                *n = sum.normalized();
+            }
+        }
+    }
+
+    //texture coordinates of the vertices of face f, projected on its plane
+    //and scaled to fit in [0, 1]
+    pub fn planar_uvs(&mut self, f : &[usize]) {
+        let (first, second) = match f {
+            [a, b, _, ..] => (self.verts[*a], self.verts[*b]),
+            _ => return
+        };
+        let n = self.face_normal(f);
+        let u_axis = second - first;
+        if n.norm() == 0.0 || u_axis.norm() == 0.0 {
+            return;
+        }
+        let u_axis = u_axis.normalized();
+        let v_axis = n.normalized().cross(u_axis);
+
+        let coords : Vec<[f64; 2]> = f.iter()
+            .map(|v| [(self.verts[*v] - first).dot(u_axis), (self.verts[*v] - first).dot(v_axis)])
+            .collect();
+        let mut min = [f64::INFINITY; 2];
+        let mut max = [f64::NEG_INFINITY; 2];
+        for c in &coords {
+            for k in 0..2 {
+                min[k] = min[k].min(c[k]);
+                max[k] = max[k].max(c[k]);
+            }
+        }
+        let size = (max[0] - min[0]).max(max[1] - min[1]);
+        for (v, c) in f.iter().zip(coords) {
+            self.uvs[*v] = [(c[0] - min[0]) / size, (c[1] - min[1]) / size];
+        }
+    }
+
+    fn verts_str(&self, shading : Shading) -> String {
         let mut res = String::new();
-        for v in self.verts {
-            res.push_str(&String::from(format!("v {} {} {}\n", v.x(), v.y(), v.z())));
+        for v in &self.verts {
+            res.push_str(&format!("v {} {} {}\n", v.x(), v.y(), v.z()));
+        }
+        for [u, v] in &self.uvs {
+            res.push_str(&format!("vt {} {}\n", u, v));
+        }
+        if shading == Shading::Smooth {
+            for (n, id) in self.normals.iter().zip(self.normal_ids()) {
+                if id.is_some() {
+                    res.push_str(&format!("vn {} {} {}\n", n.x(), n.y(), n.z()));
+                }
+            }
+        }
+
+        res
+    }
+
+    //index of the normal of each vertex among the ones written for smooth shading,
+    //vertices only used by lines have none
+    fn normal_ids(&self) -> Vec<Option<usize>> {
+        let mut used = vec![false; self.verts.len()];
+        for v in self.triangles.iter().chain(self.leaf_faces.iter().flatten()) {
+            used[*v] = true;
+        }
+        let mut n = 0;
+        used.iter()
+            .map(|u| if *u {n += 1; Some(n - 1)} else {None})
+            .collect()
+    }
+
+    //a face, with the normal record before it for flat shading.
+    //offset and n_offset are the numbers of vertices and of normals written before
+    fn face_str(&self, f : &[usize], (offset, n_offset) : (usize, usize), normal_ids : &[Option<usize>],
+                shading : Shading) -> String {
+        let mut res = String::new();
+        if shading == Shading::Flat {
+            let n = self.face_normal(f);
+            let n = if n.norm() > 0.0 {n.normalized()} else {n};
+            res.push_str(&format!("vn {} {} {}\n", n.x(), n.y(), n.z()));
+        }
+        res.push('f');
+        for v in f {
+            let i = v + offset + 1;
+            match shading {
+                Shading::Smooth => {
+                    //face vertices always have a normal
+                    let n = normal_ids[*v].unwrap_or(0) + n_offset + 1;
+                    res.push_str(&format!(" {}/{}/{}", i, i, n));
+                },
+                Shading::Flat => res.push_str(&format!(" {}/{}/-1", i, i))
+            }
         }
+        res.push('\n');
 
-        res.push_str("\ng branches\n");
+        res
+    }
 
-        for i in (0..self.triangles.len()).step_by(3) {
-            res.push_str(&String::from(format!("f {}// {}// {}//\n",
-                                               self.triangles[i] + 1,
-                                               self.triangles[i + 1] + 1,
-                                               self.triangles[i + 2] + 1)));
+    //faces of the mesh, when offset vertices and n_offset normals are written before its own.
+    //Lines have no normal
+    fn faces_str(&self, (offset, n_offset) : (usize, usize), material : &str, shading : Shading) -> String {
+        let mut res = String::from("\ng branches\n");
+
+        let normal_ids = self.normal_ids();
+        for t in self.triangles.chunks(3) {
+            res.push_str(&self.face_str(t, (offset, n_offset), &normal_ids, shading));
+        }
+        for l in self.lines.chunks(2) {
+            res.push_str(&format!("l {}/{} {}/{}\n", l[0] + offset + 1, l[0] + offset + 1,
+                                  l[1] + offset + 1, l[1] + offset + 1));
         }
 
         res.push_str("\ng leaves\n");
-        for f in self.leaf_faces {
-            res.push_str("f");
-            for v in f {
-                res.push_str(&String::from(format!(" {}//", v + 1)));
+        let mut switches = self.materials.iter().peekable();
+        for (i, f) in self.leaf_faces.iter().enumerate() {
+            while let Some((_, m)) = switches.next_if(|(start, _)| *start == i) {
+                res.push_str(&format!("usemtl {}\n", m.as_deref().unwrap_or(material)));
             }
-            res.push_str("\n");
+            res.push_str(&self.face_str(f, (offset, n_offset), &normal_ids, shading));
         }
 
         res
     }
 
+    //min and max corners, None if the mesh is empty
+    pub fn bounding_box(&self) -> Option<(Vector3, Vector3)> {
+        let first = self.verts.first()?;
+        let mut min = first.to_arr();
+        let mut max = first.to_arr();
+        for v in &self.verts {
+            let v = v.to_arr();
+            for k in 0..3 {
+                min[k] = min[k].min(v[k]);
+                max[k] = max[k].max(v[k]);
+            }
+        }
+
+        Some((Vector3::from(min), Vector3::from(max)))
+    }
+
+    pub fn scale(&mut self, f : f64) {
+        for v in &mut self.verts {
+            *v = *v * f;
+        }
+    }
+
     pub fn get_verts(&self) -> &Vec<vector3::Vector3> {
         &self.verts
     }
 
+    pub fn get_normals(&self) -> &Vec<vector3::Vector3> {
+        &self.normals
+    }
+
+    pub fn get_uvs(&self) -> &Vec<[f64; 2]> {
+        &self.uvs
+    }
+
     pub fn get_triangles(&self) -> &Vec<usize> {
         &self.triangles
     }
 
+    pub fn get_lines(&self) -> &Vec<usize> {
+        &self.lines
+    }
+
     pub fn get_leaf_faces(&self) -> &Vec<Vec<usize>> {
         &self.leaf_faces
     }
 
+    pub fn get_materials(&self) -> &Vec<(usize, Option<String>)> {
+        &self.materials
+    }
+
+    pub fn get_mtllibs(&self) -> &Vec<String> {
+        &self.mtllibs
+    }
+
     pub fn set_verts(&mut self, verts: Vec<vector3::Vector3>) {
         self.verts = verts;
     }
 
+    pub fn set_normals(&mut self, normals: Vec<vector3::Vector3>) {
+        self.normals = normals;
+    }
+
     pub fn set_triangles(&mut self, triangles: Vec<usize>) {
         self.triangles = triangles;
     }
@@ -146,3 +424,117 @@ impl Mesh {
         self.leaf_faces = leaf_faces;
     }
 }
+
+fn write_file(path : &str, contents : &str) -> Result<(), String> {
+    File::create(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| format!("could not write {}: {}", path, e))
+}
+
+//path of target relative to the directory dir
+fn relative_path(target : &str, dir : &Path) -> String {
+    let absolute = |p : &Path| p.canonicalize()
+        .or_else(|_| std::path::absolute(p))
+        .unwrap_or_else(|_| p.to_path_buf());
+    let target = absolute(Path::new(target));
+    let dir = absolute(dir);
+
+    let common = target.components().zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut res = PathBuf::new();
+    for _ in dir.components().skip(common) {
+        res.push("..");
+    }
+    for c in target.components().skip(common) {
+        res.push(c);
+    }
+
+    res.to_string_lossy().to_string()
+}
+
+//a single .obj holding all the meshes, mesh i uses the material color{i}.
+//mtllibs are the material libraries, the one defining the colors first
+pub fn obj_str(meshes : &[Mesh], mtllibs : &[String], shading : Shading) -> String {
+    let mut res = format!("mtllib {}\n", mtllibs.join(" "));
+    for m in meshes {
+        res.push_str(&m.verts_str(shading));
+    }
+
+    let (mut offset, mut n_offset) = (0, 0);
+    for (i, m) in meshes.iter().enumerate() {
+        let material = format!("color{}", i);
+        res.push_str(&format!("\nusemtl {}", material));
+        res.push_str(&m.faces_str((offset, n_offset), &material, shading));
+        offset += m.verts.len();
+        n_offset += m.normal_ids().iter().flatten().count();
+    }
+
+    res
+}
+
+//material library with one diffuse material per color index, colors are RGB in [0, 255]
+pub fn mtl_str(palette : &[(f64, f64, f64)]) -> String {
+    let mut res = String::new();
+    for (i, (r, g, b)) in palette.iter().enumerate() {
+        res.push_str(&format!("newmtl color{}\nKd {} {} {}\n\n", i, r / 255.0, g / 255.0, b / 255.0));
+    }
+
+    res
+}
+
+//write the meshes to path, using the colors of the material library mtl.
+//Imported objects keep their own material libraries
+pub fn write_obj(meshes : &[Mesh], path : &str, mtl : &str, shading : Shading) -> Result<(), String> {
+    let dir = match Path::new(path).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new(".")
+    };
+    let mut mtllibs = vec![relative_path(mtl, dir)];
+    for lib in meshes.iter().flat_map(|m| m.mtllibs.iter()) {
+        let lib = relative_path(lib, dir);
+        if !mtllibs.contains(&lib) {
+            mtllibs.push(lib);
+        }
+    }
+
+    write_file(path, &obj_str(meshes, &mtllibs, shading))
+}
+
+pub fn write_mtl(palette : &[(f64, f64, f64)], path : &str) -> Result<(), String> {
+    write_file(path, &mtl_str(palette))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_errors() {
+        let load = |name : &str, contents : &str| {
+            let path = std::env::temp_dir().join(name);
+            fs::write(&path, contents).unwrap();
+            Mesh::load(&path.to_string_lossy().to_string())
+        };
+        assert_eq!(load("graph3d_ok.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap().get_leaf_faces().len(), 1);
+        assert!(load("graph3d_vertex.obj", "v 0 x 0\n").err().unwrap().ends_with("line 1: invalid vertex `v 0 x 0`"));
+        assert!(load("graph3d_face.obj", "v 0 0 0\nf 1 2 3\n").err().unwrap().ends_with("line 2: invalid face `f 1 2 3`"));
+        assert!(load("graph3d_normal.obj", "v 0 0 0\nvn 0 1\n").is_err());
+    }
+
+    #[test]
+    fn line_normals() {
+        let mut m = Mesh::new();
+        for k in 0..5 {
+            m.add_vert(&Vector3::new(k as f64, 0.0, 0.0));
+        }
+        m.add_face(0, 1, 2);
+        m.add_line(3, 4);
+        let obj = obj_str(&[m.clone(), m], &[], Shading::Smooth);
+
+        //one normal per face vertex, lines have none
+        assert_eq!(obj.matches("vn ").count(), 6);
+        assert!(obj.contains("f 6/6/4 7/7/5 8/8/6\n"));
+        assert!(obj.contains("l 9/9 10/10\n"));
+    }
+}
@@ -1,36 +1,85 @@
-use crate::matrix4;
-use crate::mesh;
-use crate::turtle;
+use std::collections::HashMap;
+use crate::matrix4::Matrix4;
+use crate::mesh::Mesh;
+use crate::turtle::Turtle;
+use crate::vector3::Vector3;
 
+//an object placed by ~, its mesh is looked up by alias in Instances
 pub struct Object
 {
-    turtle: turtle::Turtle,
-    mesh: mesh::Mesh
+    name: String,//alias of the object in the header
+    transform: Matrix4
 }
 
 impl Object {
-    pub fn new(mesh: mesh::Mesh, turtle: turtle::Turtle) -> Object {
-        Object{mesh, turtle}
+    pub fn new(name: String, turtle: Turtle) -> Object {
+        Object{name, transform: Matrix4::transform(turtle)}
     }
 
-    pub fn get_transformed_mesh(&self) -> mesh::Mesh {
-        let mut res = mesh::Mesh::new();
-        res.set_triangles(self.mesh.get_triangles().to_vec());
-        res.set_leaf_faces(self.mesh.get_leaf_faces().to_vec());
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_transform(&self) -> &Matrix4 {
+        &self.transform
+    }
 
-        // Create transform matrix
-        let transform = matrix4::Matrix4::transform(self.turtle);
+    //copy of mesh placed at the object
+    pub fn get_transformed_mesh(&self, mesh: &Mesh) -> Mesh {
+        let mut res = mesh.clone();
 
         // Get the transformed vertices into the new mesh
         let mut verts = Vec::new();
-        for vert in self.mesh.get_verts().to_vec().iter() {
+        for vert in mesh.get_verts().iter() {
             verts.push(
-                transform.mult(*vert)
+                self.transform.mult(*vert)
             );
         }
 
         res.set_verts(verts);
+        res.set_normals(mesh.get_normals().iter().map(|n| self.transform.mult_dir(*n)).collect());
 
         res
     }
+
+    //box containing mesh placed at the object, from the placed corners of the box of mesh
+    pub fn bounding_box(&self, mesh: &Mesh) -> Option<(Vector3, Vector3)> {
+        let (lo, hi) = mesh.bounding_box()?;
+        let (lo, hi) = (lo.to_arr(), hi.to_arr());
+        let mut min = [f64::INFINITY; 3];
+        let mut max = [f64::NEG_INFINITY; 3];
+        for c in 0..8 {
+            let corner = [0, 1, 2].map(|k| if c & (1 << k) == 0 {lo[k]} else {hi[k]});
+            let p = self.transform.mult(Vector3::from(corner)).to_arr();
+            for k in 0..3 {
+                min[k] = min[k].min(p[k]);
+                max[k] = max[k].max(p[k]);
+            }
+        }
+
+        Some((Vector3::from(min), Vector3::from(max)))
+    }
+}
+
+//objects of a plant, with a single mesh per alias shared by all its instances
+#[derive(Default)]
+pub struct Instances {
+    pub meshes: HashMap<String, Mesh>,
+    pub objects: Vec<Object>
+}
+
+impl Instances {
+    pub fn mesh(&self, obj: &Object) -> &Mesh {
+        &self.meshes[obj.get_name()]
+    }
+
+    //a placed copy of the mesh of each object, for formats without instancing
+    pub fn expand(&self) -> impl Iterator<Item = Mesh> + '_ {
+        self.objects.iter().map(move |obj| obj.get_transformed_mesh(self.mesh(obj)))
+    }
+
+    //boxes of the placed objects, without copying their meshes
+    pub fn bounding_boxes(&self) -> impl Iterator<Item = (Vector3, Vector3)> + '_ {
+        self.objects.iter().filter_map(move |obj| obj.bounding_box(self.mesh(obj)))
+    }
 }
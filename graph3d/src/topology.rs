@@ -0,0 +1,172 @@
+//Branch structure of a plant: nodes are roots, branch points and tips, edges are the
+//chains of segments between them
+use std::fmt::Write;
+use serde::Serialize;
+use crate::engine::Segment;
+
+#[derive(Serialize, Debug)]
+pub struct Node {
+    pub id : usize,
+    pub position : [f64; 3]
+}
+
+#[derive(Serialize, Debug)]
+pub struct Edge {
+    pub source : usize,
+    pub target : usize,
+    pub width : f64,//width at the base
+    pub length : f64,
+    pub order : usize,//Strahler number, 1 for the edges ending at tips
+    pub segments : usize
+}
+
+#[derive(Serialize, Debug)]
+pub struct Topology {
+    pub nodes : Vec<Node>,
+    pub edges : Vec<Edge>
+}
+
+impl Topology {
+    //graph of segments linked by their parent, as made by engine::interpret
+    //or engine::process_segments
+    pub fn new(segments : &[Segment]) -> Topology {
+        let mut children = vec![Vec::new(); segments.len()];
+        for (i, s) in segments.iter().enumerate() {
+            if let Some(p) = s.parent {
+                children[p].push(i);
+            }
+        }
+
+        let mut res = Topology{nodes: Vec::new(), edges: Vec::new()};
+        let mut end_node = vec![0; segments.len()];//node at the end of each segment
+        for (i, s) in segments.iter().enumerate() {
+            //edges start at roots and branch points, segments with a single child are followed
+            let source = match s.parent {
+                Some(p) if children[p].len() == 1 => continue,
+                Some(p) => end_node[p],
+                None => res.node(s.a().pos().to_arr())
+            };
+
+            let mut last = i;
+            let mut length = (s.b().pos() - s.a().pos()).norm();
+            let mut count = 1;
+            while let [next] = children[last][..] {
+                last = next;
+                length += (segments[last].b().pos() - segments[last].a().pos()).norm();
+                count += 1;
+            }
+
+            let target = res.node(segments[last].b().pos().to_arr());
+            end_node[last] = target;
+            res.edges.push(Edge{source, target, width: s.width(), length, order: 1, segments: count});
+        }
+        res.strahler();
+
+        res
+    }
+
+    fn node(&mut self, position : [f64; 3]) -> usize {
+        self.nodes.push(Node{id: self.nodes.len(), position});
+        self.nodes.len() - 1
+    }
+
+    //the order of an edge is the highest one of its children,
+    //plus one if at least two children have it. Children come after their parent
+    fn strahler(&mut self) {
+        let mut orders : Vec<Vec<usize>> = vec![Vec::new(); self.nodes.len()];//of the edges leaving a node
+        for e in self.edges.iter_mut().rev() {
+            let children = &orders[e.target];
+            if let Some(max) = children.iter().max() {
+                let n_max = children.iter().filter(|o| *o == max).count();
+                e.order = if n_max >= 2 {max + 1} else {*max};
+            }
+            orders[e.source].push(e.order);
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Could not serialize topology.")
+    }
+
+    pub fn to_graphml(&self) -> String {
+        let mut res = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+        for (id, on, kind) in [("x", "node", "double"), ("y", "node", "double"), ("z", "node", "double"),
+                               ("width", "edge", "double"), ("length", "edge", "double"),
+                               ("order", "edge", "int"), ("segments", "edge", "int")] {
+            let _ = writeln!(res, "  <key id=\"{}\" for=\"{}\" attr.name=\"{}\" attr.type=\"{}\"/>",
+                             id, on, id, kind);
+        }
+
+        res.push_str("  <graph id=\"plant\" edgedefault=\"directed\">\n");
+        for n in &self.nodes {
+            let [x, y, z] = n.position;
+            let _ = writeln!(res, "    <node id=\"n{}\"><data key=\"x\">{}</data><data key=\"y\">{}</data>\
+                                   <data key=\"z\">{}</data></node>", n.id, x, y, z);
+        }
+        for e in &self.edges {
+            let _ = writeln!(res, "    <edge source=\"n{}\" target=\"n{}\"><data key=\"width\">{}</data>\
+                                   <data key=\"length\">{}</data><data key=\"order\">{}</data>\
+                                   <data key=\"segments\">{}</data></edge>",
+                             e.source, e.target, e.width, e.length, e.order, e.segments);
+        }
+        res.push_str("  </graph>\n</graphml>\n");
+
+        res
+    }
+
+    //GraphML if path ends with .graphml, JSON otherwise
+    pub fn write(&self, path : &str) -> Result<(), String> {
+        let contents = if path.ends_with(".graphml") {self.to_graphml()} else {self.to_json()};
+        std::fs::write(path, contents).map_err(|e| format!("could not write {}: {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{self, Params};
+
+    fn topology(s : &str) -> Topology {
+        Topology::new(&engine::interpret(engine::read_input(s).unwrap(), &Params::default()).unwrap().0)
+    }
+
+    fn orders(t : &Topology) -> Vec<usize> {
+        t.edges.iter().map(|e| e.order).collect()
+    }
+
+    #[test]
+    fn branch_point() {
+        let t = topology("FF[+F][-F]F");
+        assert_eq!(t.nodes.len(), 5);
+        assert_eq!(t.edges.len(), 4);
+
+        //the trunk up to the branch point is a single edge from the root
+        let sources : Vec<usize> = t.edges.iter().map(|e| e.source).collect();
+        assert_eq!(sources, vec![0, 1, 1, 1]);
+        assert_eq!((t.edges[0].segments, t.edges[0].length), (2, 2.0));
+        assert_eq!(orders(&t), vec![2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn nested_branch() {
+        //a single child of order 2 does not increase the order of its parent
+        let t = topology("F[+F[+F]F]F");
+        assert_eq!(t.edges.len(), 5);
+        assert_eq!(orders(&t), vec![2, 2, 1, 1, 1]);
+
+        //moves without drawing start a new root
+        let t = topology("FfF");
+        assert_eq!((t.nodes.len(), t.edges.len()), (4, 2));
+    }
+
+    #[test]
+    fn graphml() {
+        let g = topology("F[+F][-F]F").to_graphml();
+        assert!(g.starts_with("<?xml"));
+        assert!(g.ends_with("</graph>\n</graphml>\n"));
+        assert_eq!(g.matches("<node ").count(), 5);
+        assert_eq!(g.matches("<edge ").count(), 4);
+        assert!(g.contains("<edge source=\"n0\" target=\"n1\"><data key=\"width\">"));
+    }
+}
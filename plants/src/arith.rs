@@ -1,9 +1,12 @@
 use std::borrow::Borrow;
+use rand::Rng;
 
 #[derive(Clone, Debug)]
 pub enum Arith {
     Var(Var),
-    Op(ArithOp)
+    Op(ArithOp),
+    Func(ArithFunc),
+    Rand(ArithRand)
 }
 
 
@@ -11,21 +14,44 @@ impl Arith {
     pub fn eval(&self) -> f32 {
         match self {
             Arith::Var(v) => v.eval(),
-            Arith::Op(v) => v.eval()
+            Arith::Op(v) => v.eval(),
+            Arith::Func(v) => v.eval(),
+            Arith::Rand(v) => v.eval()
         }
     }
 
     pub fn vars(&self) -> Vec<&str> {
         match self {
             Arith::Var(v) => v.vars(),
-            Arith::Op(v) => v.vars()
+            Arith::Op(v) => v.vars(),
+            Arith::Func(v) => v.vars(),
+            Arith::Rand(v) => v.vars()
         }
     }
 
     pub fn set(&mut self, var: &str, val: f32) -> Result<(), ()> {
         match self {
             Arith::Var(v) => v.set(var, val),
-            Arith::Op(v) => v.set(var, val)
+            Arith::Op(v) => v.set(var, val),
+            Arith::Func(v) => v.set(var, val),
+            Arith::Rand(v) => v.set(var, val)
+        }
+    }
+
+    //draw the values of the rand() calls, after the variables are set
+    pub fn set_random<R: Rng>(&mut self, rng: &mut R) {
+        match self {
+            Arith::Var(_) => {},
+            Arith::Op(v) => {
+                v.left.set_random(rng);
+                v.right.set_random(rng);
+            },
+            Arith::Func(v) => {
+                for arg in &mut v.args {
+                    arg.set_random(rng);
+                }
+            },
+            Arith::Rand(v) => v.set_random(rng)
         }
     }
 }
@@ -83,7 +109,9 @@ pub enum OpType {
     Add,
     Sub,
     Mul,
-    Div
+    Div,
+    Mod,
+    Pow
 }
 
 impl OpType {
@@ -93,6 +121,8 @@ impl OpType {
             "-" => OpType::Sub,
             "*" => OpType::Mul,
             "/" => OpType::Div,
+            "%" => OpType::Mod,
+            "^" => OpType::Pow,
             _ => OpType::Add//error case, default to add
         }
     }
@@ -140,19 +170,248 @@ impl ArithOp {
         x / y
     }
 
+    fn modulo(x: f32, y: f32) -> f32 {
+        x % y
+    }
+
+    fn pow(x: f32, y: f32) -> f32 {
+        x.powf(y)
+    }
+
     pub fn new(op: &OpType, left: Box<Arith>, right: Box<Arith>) -> Box<Arith> {
         let fun = match op {
             OpType::Add => ArithOp::add,
             OpType::Sub => ArithOp::sub,
             OpType::Mul => ArithOp::mul,
-            OpType::Div => ArithOp::div
+            OpType::Div => ArithOp::div,
+            OpType::Mod => ArithOp::modulo,
+            OpType::Pow => ArithOp::pow
         };
         Box::new(Arith::Op(ArithOp{left, right, operator: fun}))
     }
 }
 
+type Function = fn(&[f32]) -> f32;
+
+//built-in function, or unary negation
+#[derive(Clone, Debug)]
+pub struct ArithFunc {
+    args: Vec<Arith>,
+    function: Function
+}
+
+impl ArithFunc {
+    fn eval(&self) -> f32 {
+        let args: Vec<f32> = self.args.iter().map(|x| x.eval()).collect();
+        (self.function)(&args)
+    }
+
+    fn vars(&self) -> Vec<&str> {
+        let mut res = Vec::new();
+        for arg in &self.args {
+            res.append(&mut arg.vars());
+        }
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    fn set(&mut self, var: &str, val: f32) -> Result<(), ()> {
+        let mut fail = true;
+        for arg in &mut self.args {
+            if let Ok(()) = arg.set(var, val) {
+                fail = false;
+            }
+        }
+
+        if fail {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn neg(x: &[f32]) -> f32 {
+        -x[0]
+    }
+
+    fn sqrt(x: &[f32]) -> f32 {
+        x[0].sqrt()
+    }
+
+    //sin and cos take angles in degrees, as the turtle commands
+    fn sin(x: &[f32]) -> f32 {
+        x[0].to_radians().sin()
+    }
+
+    fn cos(x: &[f32]) -> f32 {
+        x[0].to_radians().cos()
+    }
+
+    fn abs(x: &[f32]) -> f32 {
+        x[0].abs()
+    }
+
+    fn floor(x: &[f32]) -> f32 {
+        x[0].floor()
+    }
+
+    fn min(x: &[f32]) -> f32 {
+        x[0].min(x[1])
+    }
+
+    fn max(x: &[f32]) -> f32 {
+        x[0].max(x[1])
+    }
+
+    pub fn new_neg(exp: Arith) -> Box<Arith> {
+        Box::new(Arith::Func(ArithFunc{args: vec![exp], function: ArithFunc::neg}))
+    }
+
+    //number of arguments and implementation of the built-in function name,
+    //rand is not an ArithFunc, see ArithRand
+    fn lookup(name: &str) -> Option<(usize, Function)> {
+        match name {
+            "sqrt" => Some((1, ArithFunc::sqrt)),
+            "sin" => Some((1, ArithFunc::sin)),
+            "cos" => Some((1, ArithFunc::cos)),
+            "abs" => Some((1, ArithFunc::abs)),
+            "floor" => Some((1, ArithFunc::floor)),
+            "min" => Some((2, ArithFunc::min)),
+            "max" => Some((2, ArithFunc::max)),
+            _ => None
+        }
+    }
+
+    //number of arguments of the built-in function name, None if it does not exist
+    pub fn n_args(name: &str) -> Option<usize> {
+        if name == "rand" {
+            return Some(2);
+        }
+        ArithFunc::lookup(name).map(|(n, _)| n)
+    }
+
+    //create a call to the built-in function name
+    pub fn new_call(name: &str, mut args: Vec<Arith>) -> Result<Box<Arith>, &'static str> {
+        if name == "rand" {
+            return match (args.pop(), args.pop(), args.is_empty()) {
+                (Some(max), Some(min), true) => Ok(ArithRand::new_range(min, max)),
+                _ => Err("Invalid number of arguments for function.")
+            };
+        }
+
+        let (n_args, function) = ArithFunc::lookup(name).ok_or("Unknown function.")?;
+        if args.len() != n_args {
+            Err("Invalid number of arguments for function.")
+        } else {
+            Ok(Box::new(Arith::Func(ArithFunc{args, function})))
+        }
+    }
+}
+
+//rand(min, max): uniform value drawn once per rule application, see set_random
+#[derive(Clone, Debug)]
+pub struct ArithRand {
+    min: Box<Arith>,
+    max: Box<Arith>,
+    value: Option<f32>
+}
+
+impl ArithRand {
+    fn eval(&self) -> f32 {
+        //value not drawn yet, e.g. in a string built without rng: mean of the bounds
+        self.value.unwrap_or_else(|| (self.min.eval() + self.max.eval()) / 2.0)
+    }
+
+    fn vars(&self) -> Vec<&str> {
+        let mut v1 = self.min.vars();
+        let mut v2 = self.max.vars();
+        v1.append(&mut v2);
+        v1.sort();
+        v1.dedup();
+        v1
+    }
+
+    fn set(&mut self, var: &str, val: f32) -> Result<(), ()> {
+        let res1 = self.min.set(var, val);
+        let res2 = self.max.set(var, val);
+
+        match (res1, res2) {
+            (Err(_), Err(_)) => Err(()),
+            _ => Ok(())
+        }
+    }
+
+    fn set_random<R: Rng>(&mut self, rng: &mut R) {
+        self.min.set_random(rng);
+        self.max.set_random(rng);
+        let (min, max) = (self.min.eval(), self.max.eval());
+        self.value = Some(if min < max {rng.gen_range(min, max)} else {min});
+    }
+
+    fn new_range(min: Arith, max: Arith) -> Box<Arith> {
+        Box::new(Arith::Rand(ArithRand{min: Box::new(min), max: Box::new(max), value: None}))
+    }
+}
+
 pub trait ArithFactory {
     type Exp;
 
     fn create_from(exp: &Self::Exp) -> Result<Box<Arith>, &'static str>;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::symbolstring::SymbolString;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn eval(exp : &str) -> f32 {
+        SymbolString::from_string(&format!("F({})", exp)).unwrap().get_vec()[0]
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1+2*3"), 7.0);
+        assert_eq!(eval("(1+2)*3"), 9.0);
+        assert_eq!(eval("2*3^2"), 18.0);
+        assert_eq!(eval("2^3^2"), 512.0);
+        assert_eq!(eval("7%4+1"), 4.0);
+        assert_eq!(eval("10-2-3"), 5.0);
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(eval("-2^2"), -4.0);
+        assert_eq!(eval("3*-2"), -6.0);
+        assert_eq!(eval("2^-1"), 0.5);
+        assert_eq!(eval("--1"), 1.0);
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("sqrt(16)"), 4.0);
+        assert_eq!(eval("max(1,min(5,3))"), 3.0);
+        assert_eq!(eval("abs(-2)+floor(1.5)"), 3.0);
+        assert!(eval("sin(90)-1").abs() < 1e-6);
+        assert!(eval("cos(90)").abs() < 1e-6);
+        assert!(SymbolString::from_string("F(foo(1))").is_err());
+        assert!(SymbolString::from_string("F(min(1))").is_err());
+    }
+
+    #[test]
+    fn random() {
+        let mut s = SymbolString::from_string("F(rand(2,3))F(rand(2,3))").unwrap();
+        s.set_random(&mut StdRng::seed_from_u64(1));
+        let v = s.get_vec();
+        assert!(v.iter().all(|x| *x >= 2.0 && *x < 3.0));
+        assert_ne!(v[0], v[1]);
+
+        let mut s2 = SymbolString::from_string("F(rand(2,3))F(rand(2,3))").unwrap();
+        s2.set_random(&mut StdRng::seed_from_u64(1));
+        assert_eq!(v, s2.get_vec());
+
+        //not drawn
+        assert_eq!(eval("rand(2,3)"), 2.5);
+        assert!(SymbolString::from_string("F(rand(1))").is_err());
+    }
+}
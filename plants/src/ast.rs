@@ -1,9 +1,10 @@
-use crate::lexer::{self, TokenType};
+use crate::lexer::TokenType;
 
 pub struct AstNode {
     pub data : String,
     pub children : Vec<Box<AstNode>>,
-    pub node_type : TokenType
+    pub node_type : TokenType,
+    pub col : usize//position of the first token in the line
 }
 
 impl AstNode {
@@ -18,4 +19,10 @@ impl AstNode {
             c.print(depth + 1);
         }
     }
-}
\ No newline at end of file
+
+    //textual representation of the node and its children, used to compare subtrees
+    pub fn key(&self) -> String {
+        let children: Vec<String> = self.children.iter().map(|c| c.key()).collect();
+        format!("{:?}:{}({})", self.node_type, self.data, children.join(","))
+    }
+}
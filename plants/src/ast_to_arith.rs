@@ -17,14 +17,7 @@ impl ArithFactory for Arith {
                     } else {
                         for i in (1..exp.children.len()).step_by(2) {
                             //extract right operand
-                            let right = match exp.children[i + 1].node_type {
-                                TokenType::Apara | TokenType::AexpMul => {
-                                    Arith::create_from(&exp.children[i+1])
-                                },
-                                _ => {
-                                    Err("Could not convert Aexp: Expected Apara|AexpMul expression.")
-                                }
-                            }?;
+                            let right = Arith::create_from(&exp.children[i+1])?;
                             //extract operator
                             left = match exp.children[i].node_type {
                                 TokenType::Add | TokenType::Mul => {
@@ -58,7 +51,32 @@ impl ArithFactory for Arith {
                         Arith::create_from(&exp.children[0])
                     }
                 }
-            }
+            },
+            TokenType::Apow => {
+                if exp.children.len() != 2 {
+                    Err("Could not convert Apow: Expected two children.")
+                } else {
+                    Ok(arith::ArithOp::new(
+                        &arith::OpType::Pow,
+                        Arith::create_from(&exp.children[0])?,
+                        Arith::create_from(&exp.children[1])?
+                    ))
+                }
+            },
+            TokenType::Aneg => {
+                if exp.children.len() != 1 {
+                    Err("Could not convert Aneg: Expected one child.")
+                } else {
+                    Ok(arith::ArithFunc::new_neg(*Arith::create_from(&exp.children[0])?))
+                }
+            },
+            TokenType::Afunc => {
+                let mut args = Vec::new();
+                for child in &exp.children {
+                    args.push(*Arith::create_from(child)?);
+                }
+                arith::ArithFunc::new_call(&exp.data, args)
+            },
             _ => Err("Invalid expression.")
         }
     }
@@ -1,4 +1,5 @@
 use crate::arith;
+use rand::Rng;
 
 /*pub trait BoolExp {
     fn eval(&self) -> bool;
@@ -41,6 +42,22 @@ impl BoolExp {
             BoolExp::Bool(v) => v.set(var, val),
         }
     }
+
+    //draw the values of the rand() calls in the compared expressions
+    pub fn set_random<R: Rng>(&mut self, rng: &mut R) {
+        match self {
+            BoolExp::BinOp(v) => {
+                v.left.set_random(rng);
+                v.right.set_random(rng);
+            },
+            BoolExp::Unop(v) => v.exp.set_random(rng),
+            BoolExp::CompOp(v) => {
+                v.left.set_random(rng);
+                v.right.set_random(rng);
+            },
+            BoolExp::Bool(_) => {}
+        }
+    }
 }
 
 pub enum BinOpType {
@@ -0,0 +1,85 @@
+use crate::arith::Var;
+use crate::symbol::Symbol;
+use crate::symbolstring::SymbolString;
+
+//shape of the growth of a parameter over a derivation step
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GrowthFn {
+    Linear,
+    Smooth//smoothstep, starts and ends with a null speed
+}
+
+impl GrowthFn {
+    pub fn from(s: &str) -> Option<GrowthFn> {
+        match s {
+            "linear" => Some(GrowthFn::Linear),
+            "smooth" => Some(GrowthFn::Smooth),
+            _ => None
+        }
+    }
+
+    //progress in [0, 1] at time tau in [0, 1] of the step
+    pub fn eval(&self, tau: f32) -> f32 {
+        match self {
+            GrowthFn::Linear => tau,
+            GrowthFn::Smooth => tau * tau * (3.0 - 2.0 * tau)
+        }
+    }
+}
+
+//parameter of a module interpolated between derivation steps, declared with #growth
+#[derive(Clone, Debug)]
+pub struct Growth {
+    pub sym: String,
+    pub param: usize,//index of the parameter
+    pub function: GrowthFn
+}
+
+// Scale the growth parameters of the successor of pred at time tau of the step.
+// The first module of the successor with the same name as pred continues it, and its
+// parameters go from their value in pred to their new value. Other modules are new and
+// their parameters grow from 0.
+pub fn grow(successor: &mut SymbolString, pred: &Symbol, tau: f32, growth: &[Growth]) {
+    if growth.is_empty() {
+        return;
+    }
+
+    let mut continued = false;
+    for sym in &mut successor.symbols {
+        let is_continuation = !continued && sym.sym == pred.sym;
+        for g in growth {
+            if g.sym != sym.sym {
+                continue;
+            }
+            let end = match sym.get_i(g.param) {
+                Ok(v) => v,
+                Err(_) => continue
+            };
+            let start = if is_continuation {pred.get_i(g.param).unwrap_or(0.0)} else {0.0};
+            sym.params[g.param] = Var::new_value(start + (end - start) * g.function.eval(tau));
+        }
+        continued |= is_continuation;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_and_continued_modules() {
+        let growth = vec![Growth{sym: "F".to_string(), param: 0, function: GrowthFn::Linear}];
+        let pred = SymbolString::from_string("F(2)").unwrap().symbols.remove(0);
+        let mut s = SymbolString::from_string("F(4)[+(30)F(1)]").unwrap();
+        grow(&mut s, &pred, 0.25, &growth);
+
+        assert_eq!(s.to_string(), "F(2.5)[+(30)F(0.25)]");
+    }
+
+    #[test]
+    fn smooth() {
+        assert_eq!(GrowthFn::Smooth.eval(0.5), 0.5);
+        assert!(GrowthFn::Smooth.eval(0.1) < 0.1);
+        assert_eq!(GrowthFn::Smooth.eval(1.0), 1.0);
+    }
+}
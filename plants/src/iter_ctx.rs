@@ -1,10 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use crate::pattern::Pattern;
+use crate::growth::Growth;
+use crate::parse_rules;
+use crate::parse_error::LoadError;
 
 
 #[derive(Debug)]
 pub struct IterCtx {
-    pub ignored: String,//ignored characters for context test
+    pub ignored: String,//symbols ignored by the context test, separated by spaces
     pub axiom: String,//axiom used to initialize
     pub n_iter: usize,//number of iterations
     pub define: HashMap<String, f32>,//saved constants
@@ -12,26 +16,74 @@ pub struct IterCtx {
     pub patterns: Vec<Pattern>,
     pub objects: HashMap<String, String>,
     pub tropism: Vec<String>,//if vec is not empty we have to add tropism to output
+    pub seed: Option<u64>,//seed for stochastic rules, random if None
+    pub modules: Vec<String>,//multi-character module names, declared with #module
+    pub angle: Option<f32>,//rendering parameters, None to use the renderer default
+    pub step: Option<f32>,
+    pub width: Option<(f32, f32)>,//min and max
+    pub shrink: Option<f32>,
+    pub colors: BTreeMap<usize, String>,//palette entries as rrggbb, declared with #color
+    pub growth: Vec<Growth>,//parameters interpolated between steps, declared with #growth
+    pub warnings: Vec<String>,//problems that do not prevent loading, e.g. unknown directives
 }
 
 impl IterCtx {
+    //read and parse a rule file, includes are not loaded, see LSystem::load
+    pub fn load(file: &str) -> Result<IterCtx, LoadError> {
+        let data = fs::read_to_string(file)
+            .map_err(|e| LoadError::Io(file.to_string(), e))?;
+        IterCtx::parse(file, &data)
+    }
+
+    //parse rules from a string, name is only used to report errors
+    pub fn parse(name: &str, data: &str) -> Result<IterCtx, LoadError> {
+        parse_rules::parse_rules(name, data)
+            .map_err(|errors| LoadError::Parse(name.to_string(), errors))
+    }
+
     pub fn to_light_ctx(&self) -> LightCtx {
         LightCtx{ignored: self.ignored.clone(), define: self.define.clone()}
     }
 
+    //objects as (name in the output, path) pairs, the name is prefixed with the rule set
+    pub fn get_objects(&self, rule_set: &String, folder: &String) -> Vec<(String, String)> {
+        //sort objects so that the header is the same from one run to another
+        let mut objects: Vec<(&String, &String)> = self.objects.iter().collect();
+        objects.sort();
+        objects.iter()
+            .map(|(obj, file)| (format!("{}{}", rule_set, obj), format!("{}/{}", folder, file)))
+            .collect()
+    }
+
     pub fn get_object_header(&self, rule_set: &String, folder: &String) -> String {
         let mut result = String::new();
-        for (obj, file) in &self.objects {
-            let mut tmp = rule_set.clone();
-            tmp.push_str(obj);
-            tmp.push(' ');
-            tmp.push_str(format!("{}/{}", folder, file).as_str());
-            tmp.push(' ');
-            result.push_str(&tmp);
+        for (name, path) in self.get_objects(rule_set, folder) {
+            result.push_str(&format!("{} {} ", name, path));
         }
         result
     }
 
+    //rendering parameters given by directives, as "name values" pairs
+    pub fn get_render_header(&self) -> String {
+        let mut result = Vec::new();
+        if let Some(angle) = self.angle {
+            result.push(format!("angle {}", angle));
+        }
+        if let Some(step) = self.step {
+            result.push(format!("step {}", step));
+        }
+        if let Some((min, max)) = self.width {
+            result.push(format!("width {} {}", min, max));
+        }
+        if let Some(shrink) = self.shrink {
+            result.push(format!("shrink {}", shrink));
+        }
+        for (i, color) in &self.colors {
+            result.push(format!("color {} {}", i, color));
+        }
+        result.join(" ")
+    }
+
     pub fn get_tropism_header(&self) ->String {
         let mut result = String::new();
         for s in &self.tropism {//is tropism is empty, result is empty
@@ -43,6 +95,20 @@ impl IterCtx {
     }
 }
 
+//one splitmix64 step, used to derive independent seeds from a single one
+pub fn mix_seed(seed: u64, value: u64) -> u64 {
+    let mut z = (seed ^ value).wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+//derive the seed of an included rule set from the root seed
+//(stable across runs and platforms)
+pub fn sub_seed(seed: u64, rule_set: &str) -> u64 {
+    rule_set.bytes().fold(seed, |s, b| mix_seed(s, b as u64))
+}
+
 pub struct LightCtx {
     pub ignored: String,
     pub define: HashMap<String, f32>
@@ -1,39 +1,295 @@
-use crate::pattern;
+use crate::pattern::Pattern;
 use crate::symbolstring::SymbolString;
-use crate::iter_ctx::{IterCtx, LightCtx};
+use crate::iter_ctx::{self, IterCtx, LightCtx};
+use crate::growth;
 use std::collections::HashMap;
+use std::ops::Range;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::distributions::{Distribution, WeightedIndex};
+use rayon::ThreadPool;
+use rayon::prelude::*;
 
+//strings shorter than this are not worth splitting
+const MIN_CHUNK_LEN: usize = 4096;
 
-// Apply rules once from left to right on the given word.
-pub fn iterate(s : &SymbolString, ctx_list : &mut HashMap<String, IterCtx>) -> SymbolString {
+
+// Pick one rule in the group of pattern j, by weighted choice over the rule probabilities.
+fn choose_in_group(patterns : &[Pattern], j : usize, rng : &mut StdRng) -> usize {
+    let group : Vec<usize> = (0..patterns.len())
+        .filter(|k| patterns[*k].group == patterns[j].group)
+        .collect();
+    if group.len() == 1 {
+        return j;
+    }
+
+    match WeightedIndex::new(group.iter().map(|k| patterns[*k].p)) {
+        Ok(dist) => group[dist.sample(rng)],
+        Err(_) => j//all weights are null, keep the first matching rule
+    }
+}
+
+// Rewrite the symbols of s in range. Contexts are tested on the whole string.
+// A rule with several symbols in its predecessor consumes all of them.
+// If tau is given, successors are taken at this time of the step (see growth::grow).
+fn rewrite(s : &SymbolString, range : Range<usize>,
+           ctx_list : &HashMap<String, IterCtx>,
+           light_ctx : &HashMap<String, LightCtx>,
+           seeds : &HashMap<String, u64>, step : usize,
+           tau : Option<f32>) -> SymbolString {
     let mut result = SymbolString::empty();
 
-    let light_ctx : HashMap<String, LightCtx> = ctx_list.iter()
+    let mut i = range.start;
+    while i < range.end {
+        let mut n = 0;//number of symbols replaced, 0 if no rule applies
+        let rule_set = &s.symbols[i].rule_set;
+        if let (Some(ctx), Some(seed)) = (ctx_list.get(rule_set), seeds.get(rule_set)) {
+            //the generator is seeded from the symbol position so that the random
+            //choices do not depend on the order in which symbols are rewritten
+            let mut rng = StdRng::seed_from_u64(
+                iter_ctx::mix_seed(iter_ctx::mix_seed(*seed, step as u64), i as u64));
+            //all the rules of a group match the same symbols with the same bindings,
+            //find the first matching group
+            let matched = ctx.patterns.iter().enumerate()
+                .find_map(|(j, p)| {
+                    p.test(i, s, &light_ctx[rule_set], &mut rng).map(|b| (j, b))
+                });
+            if let Some((j, bindings)) = matched {
+                let k = choose_in_group(&ctx.patterns, j, &mut rng);
+                let mut successor = ctx.patterns[k]
+                    .instantiate(&bindings, &light_ctx[rule_set], &mut rng);
+                if let Some(tau) = tau {
+                    growth::grow(&mut successor, &s.symbols[i], tau, &ctx.growth);
+                }
+                result.symbols.append(&mut successor.symbols);
+                n = ctx.patterns[j].pred_len();
+            }
+        }
+        if n == 0 {
+            result.push(s.symbols[i].clone());
+            n = 1;
+        }
+        i += n;
+    }
+
+    result
+}
+
+// Move a chunk boundary forward until no predecessor of several symbols can match
+// across it. The rules are then applied on each side as in the sequential scan.
+fn split_point(s : &SymbolString, b : usize, ctx_list : &HashMap<String, IterCtx>,
+               max_len : usize) -> usize {
+    let crosses = |b : usize| {
+        (b.saturating_sub(max_len - 1)..b).any(|i| {
+            match ctx_list.get(&s.symbols[i].rule_set) {
+                Some(ctx) => ctx.patterns.iter()
+                    .any(|p| i + p.pred_len() > b && p.pred_matches(i, s)),
+                None => false
+            }
+        })
+    };
+
+    let mut b = b;
+    while b < s.len() && crosses(b) {
+        b += 1;
+    }
+    b
+}
+
+fn light_ctx(ctx_list : &HashMap<String, IterCtx>) -> HashMap<String, LightCtx> {
+    ctx_list.iter()
         .map(|(s, ctx)| -> (String, LightCtx) {
             (s.clone(), ctx.to_light_ctx())
-    }).collect();
-
-    for i in 0..s.len() {
-        let mut found = false;
-        match ctx_list.get_mut(&s.symbols[i].rule_set) {
-            Some(ctx) => {
-                //println!("{:?}", ctx);
-                for p in ctx.patterns.iter_mut() {
-                    //println!("{:?}", p);
-                    if p.test(i, s, &light_ctx[&s.symbols[i].rule_set]) {
-                        result.push_str(&p.replacement);
-                        found = true;
-                        break;
-                    }
-                }},
-            _ => {
-                //println!("Could not find IterCtx: {}", &s.symbols[i].rule_set);
-            }
-        };
-        if !found {
-            result.push(s.symbols[i].clone())
+    }).collect()
+}
+
+// Apply rules once from left to right on the given word.
+// seeds holds the seed of each rule set, step is the number of the current derivation step.
+pub fn iterate(s : &SymbolString, ctx_list : &HashMap<String, IterCtx>,
+               seeds : &HashMap<String, u64>, step : usize) -> SymbolString {
+    let light_ctx = light_ctx(ctx_list);
+    rewrite(s, 0..s.len(), ctx_list, &light_ctx, seeds, step, None)
+}
+
+// Same as iterate, but the word is split in chunks rewritten on the threads of pool.
+// The result does not depend on the number of threads.
+pub fn iterate_par(s : &SymbolString, ctx_list : &HashMap<String, IterCtx>,
+                   seeds : &HashMap<String, u64>, step : usize,
+                   pool : &ThreadPool) -> SymbolString {
+    rewrite_par(s, ctx_list, seeds, step, pool, None)
+}
+
+// Apply rules once, with the growth parameters of the successors taken at time tau
+// in [0, 1] of the step. tau = 1 gives the same result as iterate.
+// The word is rewritten on the threads of pool if given.
+pub fn iterate_growing(s : &SymbolString, ctx_list : &HashMap<String, IterCtx>,
+                       seeds : &HashMap<String, u64>, step : usize, tau : f32,
+                       pool : Option<&ThreadPool>) -> SymbolString {
+    match pool {
+        Some(pool) => rewrite_par(s, ctx_list, seeds, step, pool, Some(tau)),
+        None => {
+            let light_ctx = light_ctx(ctx_list);
+            rewrite(s, 0..s.len(), ctx_list, &light_ctx, seeds, step, Some(tau))
         }
     }
+}
+
+fn rewrite_par(s : &SymbolString, ctx_list : &HashMap<String, IterCtx>,
+               seeds : &HashMap<String, u64>, step : usize,
+               pool : &ThreadPool, tau : Option<f32>) -> SymbolString {
+    let light_ctx = light_ctx(ctx_list);
+    let n_chunks = (s.len() / MIN_CHUNK_LEN).min(pool.current_num_threads() * 4).max(1);
+    let chunk_len = s.len().div_ceil(n_chunks);
+
+    //longest predecessor, chunks can be split anywhere if it is 1
+    let max_len = ctx_list.values()
+        .flat_map(|ctx| ctx.patterns.iter().map(|p| p.pred_len()))
+        .max()
+        .unwrap_or(1);
+    let mut bounds = vec![0];
+    for c in 1..n_chunks {
+        let b = (c * chunk_len).min(s.len());
+        let b = if max_len > 1 {split_point(s, b, ctx_list, max_len)} else {b};
+        bounds.push(b.max(bounds[c - 1]));
+    }
+    bounds.push(s.len());
+
+    let chunks : Vec<SymbolString> = pool.install(|| {
+        (0..n_chunks).into_par_iter()
+            .map(|c| {
+                rewrite(s, bounds[c]..bounds[c + 1], ctx_list, &light_ctx, seeds, step, tau)
+            })
+            .collect()
+    });
+
+    let mut result = SymbolString{
+        symbols: Vec::with_capacity(chunks.iter().map(|c| c.len()).sum())
+    };
+    for mut chunk in chunks {
+        result.symbols.append(&mut chunk.symbols);
+    }
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_rules, LSystem};
+
+    fn derive_threads(rules : &str, seed : u64, n_threads : usize) -> String {
+        let mut ctx = parse_rules::parse_rules("test", rules).unwrap();
+        for p in &mut ctx.patterns {
+            p.rule_set(&"root".to_string());
+        }
+        let mut res = SymbolString::from_string_with(&ctx.axiom, &ctx.modules).unwrap();
+        res.rule_set(&"root".to_string());
+        let n_iter = ctx.n_iter;
+
+        let mut ctx_list = HashMap::new();
+        ctx_list.insert("root".to_string(), ctx);
+        let mut seeds = HashMap::new();
+        seeds.insert("root".to_string(), seed);
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(n_threads).build().unwrap();
+        for step in 0..n_iter {
+            res = if n_threads > 1 {
+                iterate_par(&res, &ctx_list, &seeds, step, &pool)
+            } else {
+                iterate(&res, &ctx_list, &seeds, step)
+            };
+        }
+        res.to_string()
+    }
+
+    fn derive(rules : &str, seed : u64) -> String {
+        derive_threads(rules, seed, 1)
+    }
+
+    #[test]
+    fn seeded_output() {
+        let rules = "#axiom AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\n#niter 2\nA@0.5->A[+A]\nA->FA\n";
+
+        assert_eq!(derive(rules, 42), derive(rules, 42));
+        assert_ne!(derive(rules, 42), derive(rules, 43));
+
+        //#seed in the file is the same as the seed given to the L-system
+        let l = LSystem::new(IterCtx::parse("test", &format!("#seed 42\n{}", rules)).unwrap(),
+                             "test", ".", None).unwrap();
+        let l_arg = LSystem::new(IterCtx::parse("test", rules).unwrap(), "test", ".", Some(42)).unwrap();
+        assert_eq!(l.seed, 42);
+        assert_eq!(l.derive_last(2).to_string(), derive(rules, 42));
+        assert_eq!(l_arg.derive_last(2).to_string(), derive(rules, 42));
+
+        //included rule sets do not share the root seed
+        assert_ne!(iter_ctx::sub_seed(42, "leaf"), 42);
+        assert_ne!(iter_ctx::sub_seed(42, "leaf"), iter_ctx::sub_seed(42, "flower"));
+        assert_ne!(iter_ctx::sub_seed(42, "leaf"), iter_ctx::sub_seed(43, "leaf"));
+    }
+
+    #[test]
+    fn module_names() {
+        let rules = "#module Apex Internode\n#axiom Apex(1)\n#niter 3\n#ignore +\n\
+                     Apex(x)->Internode(x)[+L]Apex(x+1)\n\
+                     Internode(x)<Apex(y)->Apex(y*2)\n\
+                     Internode(x)->FInternode(x)\n";
+
+        assert_eq!(derive(rules, 0), "FFInternode(1)[+L]Apex(8)");
+        //without declaration, letters are separate modules
+        assert_eq!(derive("#axiom Apex\n#niter 1\nA->D\n", 0), "Dpex");
+        //ignoring a name does not ignore its letters
+        assert_eq!(derive("#module Apex\n#ignore Apex\n#axiom BAC\n#niter 1\nB<C->X\n", 0), "BAC");
+        assert_eq!(derive("#module Apex\n#ignore Apex\n#axiom BApexC\n#niter 1\nB<C->X\n", 0), "BApexX");
+        assert_eq!(derive("#ignore +-\n#axiom B+-C\n#niter 1\nB<C->X\n", 0), "B+-X");
+    }
+
+    #[test]
+    fn group_weights() {
+        let axiom = "A".repeat(1000);
+        let rules = format!("#axiom {}\n#niter 1\nA@0.2->B\nA@0.8->C\n", axiom);
+        let res = derive(&rules, 1);
+        let n_b = res.chars().filter(|c| *c == 'B').count();
+
+        assert_eq!(res.len(), 1000);
+        assert!(n_b > 150 && n_b < 250);
+    }
+
+    #[test]
+    fn group_implicit_weight() {
+        let ctx = parse_rules::parse_rules("test", "A@0.3->B\nA->C\nB->C\n").unwrap();
+        let p: Vec<f32> = ctx.patterns.iter().map(|p| p.p).collect();
+
+        assert_eq!(ctx.patterns[0].group, ctx.patterns[1].group);
+        assert_ne!(ctx.patterns[0].group, ctx.patterns[2].group);
+        assert!((p[0] - 0.3).abs() < 0.001 && (p[1] - 0.7).abs() < 0.001);
+    }
+
+    #[test]
+    fn multi_symbol_predecessor() {
+        let rules = "#axiom F(1)F(2)F(3)F(4)F(5)\n#niter 1\nF(x)F(y)->F(x+y)\n";
+
+        assert_eq!(derive(rules, 0), "F(3)F(7)F(5)");
+        //longer predecessors are tried first, whatever the order of the rules
+        assert_eq!(derive("#axiom ABA\n#niter 1\nA->C\nAB->D\n", 0), "DC");
+    }
+
+    #[test]
+    fn parallel_multi_symbol() {
+        let rules = "#axiom A\n#niter 20\nA@0.5->AB[A]\nA->BA\nBB->A\nB[->[C\n";
+        let res = derive(rules, 3);
+
+        assert!(res.len() > 4 * MIN_CHUNK_LEN);
+        assert_eq!(res, derive_threads(rules, 3, 2));
+        assert_eq!(res, derive_threads(rules, 3, 7));
+    }
+
+    #[test]
+    fn parallel_same_output() {
+        let rules = "#axiom A\n#niter 11\nA@0.5->B[+A]A\nA->A[-A]B\nB<B->BF(2)\n";
+        let res = derive(rules, 5);
+
+        assert!(res.len() > MIN_CHUNK_LEN);
+        assert_eq!(res, derive_threads(rules, 5, 2));
+        assert_eq!(res, derive_threads(rules, 5, 7));
+    }
+}
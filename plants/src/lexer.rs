@@ -1,4 +1,6 @@
+use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::ops::Deref;
 
 #[derive(PartialEq, Debug)]
 pub enum TokenType {
@@ -26,6 +28,9 @@ pub enum TokenType {
     Number,
     Anum,
     Apara,
+    Apow,
+    Aneg,
+    Afunc,
     AexpMul,
     Aexp,
     CompExp,
@@ -46,66 +51,107 @@ pub enum TokenType {
 pub struct Token {
     pub toktype : TokenType,
     pub val : String,
+    pub col : usize,//position of the first character in the line
 }
 
-pub fn lexer(rules : &str) -> VecDeque<Token> {
+//list of tokens, along with the furthest position where the parser failed
+//and the tokens it expected there
+pub struct Tokens {
+    tokens : VecDeque<Token>,
+    failure : RefCell<(usize, Vec<String>)>
+}
+
+impl Tokens {
+    //record that the token expected was not found at index
+    pub fn expect(&self, index : usize, expected : &str) {
+        let mut failure = self.failure.borrow_mut();
+        if index > failure.0 {
+            *failure = (index, Vec::new());
+        }
+        if index == failure.0 && !failure.1.iter().any(|e| e == expected) {
+            failure.1.push(expected.to_string());
+        }
+    }
+
+    pub fn failure(&self) -> (usize, Vec<String>) {
+        self.failure.borrow().clone()
+    }
+
+    //restore a failure state, used to forget the errors of an optional rule
+    pub fn restore(&self, failure : (usize, Vec<String>)) {
+        *self.failure.borrow_mut() = failure;
+    }
+}
+
+impl Deref for Tokens {
+    type Target = VecDeque<Token>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tokens
+    }
+}
+
+//modules are the multi-character module names declared with #module.
+//They are matched before single letters, the longest name first, so that
+//grammars without declarations are lexed one letter at a time.
+//A name ends before an uppercase letter or a non-letter: with Apex declared,
+//ApexF is Apex then F, and Apexes is an error rather than Apex, e, s.
+pub fn lexer(rules : &str, modules : &[String]) -> Tokens {
     let mut tokens : VecDeque<Token> = VecDeque::new();
+    let mut modules : Vec<&String> = modules.iter().collect();
+    modules.sort_by_key(|m| std::cmp::Reverse(m.len()));
 
     for line in rules.lines() {
         let mut i = 0;
 
         while i < line.len() {
+            let start = i;
             if line.chars().nth(i)  == Some('<') {
                 if line.chars().nth(i + 1) == Some('=') {
                     tokens.push_back(Token{toktype: TokenType::CompOp,
-                        val: String::from("<=")});
+                        val: String::from("<="), col: start});
                     i += 1;
                 } else {
-                    tokens.push_back(Token { toktype: TokenType::Lsep,
-                        val: String::from("<") });
+                    tokens.push_back(Token{toktype: TokenType::Lsep,
+                        val: String::from("<"), col: start });
                 }
             } else if line.chars().nth(i) == Some('>') {
                 if line.chars().nth(i + 1) == Some('=') {
                     tokens.push_back(Token{toktype: TokenType::CompOp,
-                        val: String::from(">=")});
+                        val: String::from(">="), col: start});
                     i += 1;
                 } else {
-                    tokens.push_back(Token { toktype: TokenType::Rsep,
-                        val: String::from(">") });
+                    tokens.push_back(Token{toktype: TokenType::Rsep,
+                        val: String::from(">"), col: start });
                 }
             } else if line.chars().nth(i) == Some('='){
                 tokens.push_back(Token{toktype: TokenType::CompOp,
-                    val: String::from("=")});
+                    val: String::from("="), col: start});
             } else if line.chars().nth(i) == Some('!') && line.chars().nth(i + 1) == Some('=') {
-                tokens.push_back(Token{toktype: TokenType::CompOp, val: String::from("!=")});
+                tokens.push_back(Token{toktype: TokenType::CompOp, val: String::from("!="), col: start});
                 i += 1;
             } else if line.chars().nth(i) == Some('(') {
-                tokens.push_back(Token{toktype : TokenType::Lpara, val : String::from("(")});
+                tokens.push_back(Token{toktype : TokenType::Lpara, val : String::from("("), col: start});
             } else if line.chars().nth(i) == Some(')') {
-                tokens.push_back(Token{toktype : TokenType::Rpara, val : String::from(")")});
+                tokens.push_back(Token{toktype : TokenType::Rpara, val : String::from(")"), col: start});
             } else if line.chars().nth(i) == Some('@') {
-                tokens.push_back(Token{toktype : TokenType::Psep, val : String::from("@")});
+                tokens.push_back(Token{toktype : TokenType::Psep, val : String::from("@"), col: start});
             } else if line.chars().nth(i) == Some(':') {
-                tokens.push_back(Token{toktype : TokenType::Condsep, val : String::from(":")});
+                tokens.push_back(Token{toktype : TokenType::Condsep, val : String::from(":"), col: start});
             } else if line.chars().nth(i) == Some('#') {
-                tokens.push_back(Token{toktype : TokenType::PreprocStart, val : String::from("#")});
+                tokens.push_back(Token{toktype : TokenType::PreprocStart, val : String::from("#"), col: start});
             } else if line.chars().nth(i) == Some('-') && line.chars().nth(i+1) == Some('>') {
-                tokens.push_back(Token{toktype : TokenType::Patsep, val : String::from("->")});
+                tokens.push_back(Token{toktype : TokenType::Patsep, val : String::from("->"), col: start});
                 i += 1;
             } else if line.chars().nth(i) == Some(' ') || line.chars().nth(i) == Some('\n') {
                 tokens.push_back(Token{toktype : TokenType::Ws,
-                    val : line.chars().nth(i).unwrap().to_string()});
-            } else if line.chars().nth(i).unwrap().is_digit(10)
-                || line.chars().nth(i) == Some('-')
-                && line.chars().nth(i + 1).unwrap_or('-').is_digit(10) {
+                    val : line.chars().nth(i).unwrap().to_string(), col: start});
+            } else if line.chars().nth(i).unwrap().is_digit(10) {
                 let mut dot = false;
                 //while we have something to read and we are reading digits or a dot
+                //a minus sign is lexed as a Char, it is either an operator or a negation
                 let mut c = line.chars().nth(i).unwrap();
                 let mut s = String::new();
-                if c == '-' {
-                    i += 1;
-                    s.push(c);
-                }
                 while i < line.len() && (c.is_digit(10)
                     || (c == '.' && !dot)) {
                     s.push(c);
@@ -119,18 +165,34 @@ pub fn lexer(rules : &str) -> VecDeque<Token> {
                 }
 
                 tokens.push_back(Token{toktype: TokenType::Number,
-                    val : s});
+                    val : s, col: start});
 
                 i -= 1;//avoid consuming the next char
+            } else if let Some(m) = modules.iter()
+                .find(|m| line.chars().skip(i).take(m.len()).eq(m.chars())) {
+                //a name followed by a lowercase letter, a digit or '_' is a longer
+                //identifier that was not declared, it is an error token
+                let next = line.chars().nth(i + m.len());
+                if next.is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+                    let name : String = line.chars().skip(i)
+                        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                        .collect();
+                    i += name.len() - 1;
+                    tokens.push_back(Token{toktype : TokenType::Err, val : name, col: start});
+                } else {
+                    tokens.push_back(Token{toktype : TokenType::Letter,
+                        val : m.to_string(), col: start});
+                    i += m.len() - 1;
+                }
             } else {
                 let val = line.chars().nth(i).unwrap();
                 if val.is_alphabetic() {
                     tokens.push_back(Token{toktype : TokenType::Letter,
-                        val : val.to_string()});
+                        val : val.to_string(), col: start});
                 }
                 else {
                     tokens.push_back(Token{toktype : TokenType::Char,
-                        val : val.to_string()});
+                        val : val.to_string(), col: start});
                 }
             }
 
@@ -138,5 +200,5 @@ pub fn lexer(rules : &str) -> VecDeque<Token> {
         }
     }
 
-    tokens
+    Tokens{tokens, failure: RefCell::new((0, Vec::new()))}
 }
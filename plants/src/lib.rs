@@ -0,0 +1,25 @@
+//L-system derivation: loading rule files, rewriting and writing the results.
+//See LSystem for the entry point.
+
+pub mod pattern;
+mod iterate;
+mod parse_rules;
+mod lexer;
+mod ast;
+mod arith;
+mod ast_to_arith;
+mod bool_exp;
+mod ast_to_boolexp;
+pub mod symbol;
+pub mod symbolstring;
+pub mod iter_ctx;
+pub mod parse_error;
+pub mod output;
+pub mod lsystem;
+pub mod growth;
+
+pub use iter_ctx::IterCtx;
+pub use lsystem::LSystem;
+pub use pattern::Pattern;
+pub use symbol::Symbol;
+pub use symbolstring::SymbolString;
@@ -0,0 +1,356 @@
+use std::collections::{BTreeMap, HashMap};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rayon::ThreadPool;
+use crate::iter_ctx::{self, IterCtx};
+use crate::iterate;
+use crate::output::{self, Format};
+use crate::parse_error::LoadError;
+use crate::symbolstring::SymbolString;
+
+//a grammar with its included rule sets, ready to be derived
+pub struct LSystem {
+    rule_sets: HashMap<String, IterCtx>,//"root" and the include aliases
+    seeds: HashMap<String, u64>,//one seed per rule set
+    axiom: SymbolString,
+    folder: String,//included files and objects are relative to this folder
+    pub seed: u64,
+    pub warnings: Vec<String>,//warnings of the rule file and its includes, prefixed with the file
+    pool: Option<ThreadPool>//None for the sequential mode
+}
+
+impl LSystem {
+    //load a rule file and its includes, seed overrides the #seed directive
+    pub fn load(file: &str, seed: Option<u64>) -> Result<LSystem, LoadError> {
+        let mut path_split = file.rsplitn(2, "/");
+        path_split.next();
+        let folder = match path_split.next() {
+            Some(s) => s.to_string(),
+            _ => ".".to_string()
+        };
+
+        LSystem::new(IterCtx::load(file)?, file, &folder, seed)
+    }
+
+    //build the L-system from parsed root rules, included files are read from folder.
+    //name is only used to report errors
+    pub fn new(mut ctx: IterCtx, name: &str, folder: &str, seed: Option<u64>) -> Result<LSystem, LoadError> {
+        let seed = seed.or(ctx.seed).unwrap_or_else(rand::random);
+
+        for r in &mut ctx.patterns {
+            r.rule_set(&"root".to_string());
+        }
+
+        // Parse included rules
+        let mut rule_sets : HashMap<String, IterCtx> = HashMap::new();
+        let mut shapes_res : HashMap<String, SymbolString> = HashMap::new();
+
+        let mut warnings : Vec<String> = ctx.warnings.iter()
+            .map(|w| format!("{}: {}", name, w))
+            .collect();
+
+        for (alias, file) in ctx.include.iter() {
+            let path = format!("{}/{}", folder, file);
+            let mut shape_ctx = IterCtx::load(&path)?;
+            warnings.extend(shape_ctx.warnings.iter().map(|w| format!("{}: {}", path, w)));
+            for pat in &mut shape_ctx.patterns {
+                pat.rule_set(alias);
+                for obj in shape_ctx.objects.keys() {
+                    pat.set_obj(obj);
+                }
+            }
+
+            let mut shape_res = SymbolString::from_string_with(&shape_ctx.axiom,
+                                                               &shape_ctx.modules)
+                .map_err(|e| LoadError::Axiom(path, e))?;
+            shape_res.rule_set(alias);
+            shapes_res.insert(alias.to_string(), shape_res);
+            rule_sets.insert(alias.to_string(), shape_ctx);
+        }
+
+        //replace alias in "root" to the correct value
+        for pat in &mut ctx.patterns {
+            for (alias, value) in &shapes_res {
+                pat.replace(alias, value);
+            }
+            for obj in ctx.objects.keys() {
+                pat.set_obj(obj);
+            }
+        }
+
+        let mut axiom = SymbolString::from_string_with(&ctx.axiom, &ctx.modules)
+            .map_err(|e| LoadError::Axiom(name.to_string(), e))?;
+        axiom.rule_set(&"root".to_string());
+        //rand() calls of the axiom are drawn once, from the seed
+        axiom.set_random(&mut StdRng::seed_from_u64(seed));
+
+        //add root ctx to IterCtx map
+        rule_sets.insert("root".to_string(), ctx);
+
+        //one seed per rule set, included rule sets get a seed derived from the root one
+        let seeds = rule_sets.keys()
+            .map(|rule_set| {
+                let sub_seed = if rule_set == "root" {
+                    seed
+                } else {
+                    iter_ctx::sub_seed(seed, rule_set)
+                };
+                (rule_set.clone(), sub_seed)
+            }).collect();
+
+        Ok(LSystem{rule_sets, seeds, axiom, folder: folder.to_string(), seed, warnings, pool: None})
+    }
+
+    //rewrite on n_threads threads, 1 for the sequential mode.
+    //The pool is kept for all the following steps
+    pub fn set_threads(&mut self, n_threads: usize) {
+        self.pool = if n_threads > 1 {
+            Some(rayon::ThreadPoolBuilder::new()
+                 .num_threads(n_threads)
+                 .build()
+                 .expect("Could not create thread pool."))
+        } else {
+            None
+        };
+    }
+
+    //number of derivation steps given by #niter
+    pub fn n_iter(&self) -> usize {
+        self.rule_sets["root"].n_iter
+    }
+
+    //rule set of the loaded file, without its includes
+    pub fn root(&self) -> &IterCtx {
+        &self.rule_sets["root"]
+    }
+
+    pub fn axiom(&self) -> &SymbolString {
+        &self.axiom
+    }
+
+    //apply the rules once on s, step is the number of the derivation step
+    pub fn step(&self, s: &SymbolString, step: usize) -> SymbolString {
+        match &self.pool {
+            Some(pool) => iterate::iterate_par(s, &self.rule_sets, &self.seeds, step, pool),
+            None => iterate::iterate(s, &self.rule_sets, &self.seeds, step)
+        }
+    }
+
+    //step applied on s, with the growth parameters taken at time tau in [0, 1] of the step
+    pub fn grow_step(&self, s: &SymbolString, step: usize, tau: f32) -> SymbolString {
+        iterate::iterate_growing(s, &self.rule_sets, &self.seeds, step, tau, self.pool.as_ref())
+    }
+
+    //string at time t, the fractional part of t is the progress of the next step
+    pub fn at(&self, t: f32) -> SymbolString {
+        let n = t.floor() as usize;
+        let tau = t - n as f32;
+        let s = self.derive_last(n);
+        if tau > 0.0 {
+            self.grow_step(&s, n, tau)
+        } else {
+            s
+        }
+    }
+
+    //iterator over the n first derivation steps, from the axiom
+    pub fn derive(&self, n: usize) -> Derivation<'_> {
+        Derivation{lsystem: self, current: self.axiom.clone(), step: 0, n}
+    }
+
+    //string after n derivation steps, the axiom if n is 0.
+    //Unlike derive, the intermediate steps are not copied
+    pub fn derive_last(&self, n: usize) -> SymbolString {
+        let mut s = self.axiom.clone();
+        for step in 0..n {
+            s = self.step(&s, step);
+        }
+        s
+    }
+
+    //rule sets are sorted so that the header is the same from one run to another
+    fn sorted_rule_sets(&self) -> Vec<&String> {
+        let mut rule_sets : Vec<&String> = self.rule_sets.keys().collect();
+        rule_sets.sort();
+        rule_sets
+    }
+
+    //header lines of the text output: tropism, objects and multi-character module names
+    pub fn text_header(&self) -> String {
+        let rule_sets = self.sorted_rule_sets();
+
+        let mut tropism_str = String::from("@");
+        for rule_set in &rule_sets {
+            tropism_str.push_str(&self.rule_sets[*rule_set].get_tropism_header());
+        }
+
+        let mut header = if tropism_str.len() > 1 {
+            tropism_str.push('\n');
+            tropism_str
+        } else {
+            String::new()
+        };
+
+        let mut output_header = String::from("#");
+        for rule_set in &rule_sets {
+            output_header.push_str(&self.rule_sets[*rule_set]
+                .get_object_header(rule_set, &self.folder));
+        }
+        if output_header.len() > 1 {
+            header.push_str(&output_header);
+            header.push('\n');
+        }
+
+        //multi-character module names, so that readers do not split them into letters
+        let mut modules : Vec<&str> = rule_sets.iter()
+            .flat_map(|rule_set| self.rule_sets[*rule_set].modules.iter().map(|m| m.as_str()))
+            .collect();
+        modules.sort();
+        modules.dedup();
+        if !modules.is_empty() {
+            header.push_str(&format!(":{}\n", modules.join(" ")));
+        }
+
+        let render = self.root().get_render_header();
+        if !render.is_empty() {
+            header.push_str(&format!("={}\n", render));
+        }
+
+        header
+    }
+
+    //same header for the JSON output
+    pub fn json_header(&self) -> output::Header {
+        let rule_sets = self.sorted_rule_sets();
+
+        output::Header{
+            tropism: rule_sets.iter()
+                .map(|rule_set| &self.rule_sets[*rule_set].tropism)
+                .find(|t| !t.is_empty())
+                .map(|t| {
+                    let mut v = [0.0; 4];
+                    for (i, s) in t.iter().enumerate() {
+                        v[i] = s.parse().unwrap();//checked by read_preproc
+                    }
+                    v
+                }),
+            objects: rule_sets.iter()
+                .flat_map(|rule_set| self.rule_sets[*rule_set].get_objects(rule_set, &self.folder))
+                .collect::<BTreeMap<String, String>>(),
+            rule_sets: rule_sets.iter().map(|rule_set| rule_set.to_string()).collect(),
+            render: output::Render{
+                angle: self.root().angle,
+                step: self.root().step,
+                width: self.root().width.map(|(min, max)| [min, max]),
+                shrink: self.root().shrink,
+                colors: self.root().colors.clone()
+            }
+        }
+    }
+
+    //derived string with its header, in the given format
+    pub fn output(&self, format: Format, contents: &SymbolString) -> String {
+        match format {
+            Format::Text => format!("{}{}", self.text_header(), contents.to_string()),
+            Format::Json => output::to_json(&self.json_header(), contents)
+        }
+    }
+}
+
+//derivation steps of an L-system, see LSystem::derive
+pub struct Derivation<'a> {
+    lsystem: &'a LSystem,
+    current: SymbolString,
+    step: usize,
+    n: usize
+}
+
+impl<'a> Iterator for Derivation<'a> {
+    type Item = SymbolString;
+
+    fn next(&mut self) -> Option<SymbolString> {
+        if self.step >= self.n {
+            return None;
+        }
+
+        self.current = self.lsystem.step(&self.current, self.step);
+        self.step += 1;
+        Some(self.current.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lsystem(rules: &str) -> LSystem {
+        LSystem::new(IterCtx::parse("test", rules).unwrap(), "test", ".", Some(1)).unwrap()
+    }
+
+    #[test]
+    fn axiom_error_file() {
+        let dir = std::env::temp_dir().join("plants_axiom_error");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("bad_axiom");
+        std::fs::write(&file, "#axiom F(\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        match LSystem::load(file, Some(1)) {
+            Err(e) => assert!(e.to_string().starts_with(&format!("error: invalid axiom in {}:", file))),
+            Ok(_) => panic!("invalid axiom was accepted")
+        }
+    }
+
+    #[test]
+    fn derive_steps() {
+        let l = lsystem("#axiom A\n#niter 3\nA->AB\nB->A\n");
+        let steps: Vec<String> = l.derive(l.n_iter()).map(|s| s.to_string()).collect();
+
+        assert_eq!(steps, vec!["AB", "ABA", "ABAAB"]);
+        assert_eq!(l.derive_last(3).to_string(), "ABAAB");
+        assert_eq!(l.derive_last(0).to_string(), "A");
+    }
+
+    #[test]
+    fn threads_same_output() {
+        let rules = "#axiom A\n#niter 12\n#growth F 0\nA->F(1)[+A]A\n";
+        let l = lsystem(rules);
+        let mut l_par = lsystem(rules);
+        l_par.set_threads(3);
+
+        assert_eq!(l.derive_last(12).to_string(), l_par.derive_last(12).to_string());
+        assert_eq!(l.at(11.5).to_string(), l_par.at(11.5).to_string());
+    }
+
+    #[test]
+    fn random_axiom() {
+        let v = lsystem("#axiom F(rand(2,3))\n").axiom().get_vec()[0];
+
+        assert!((2.0..3.0).contains(&v) && v != 2.5);
+    }
+
+    #[test]
+    fn continuous_growth() {
+        let l = lsystem("#axiom A(1)\n#growth F 0\n#growth A 0\nA(x)->F(2)A(x+1)\n");
+
+        assert_eq!(l.at(1.5).to_string(), "F(2)F(1)A(2.5)");
+        assert_eq!(l.at(2.0).to_string(), l.derive_last(2).to_string());
+        assert_eq!(l.grow_step(l.axiom(), 0, 1.0).to_string(), "F(2)A(2)");
+    }
+
+    #[test]
+    fn text_header() {
+        let l = lsystem("#module Apex\n#tropism 0 0 -1 0.2\n#axiom Apex\n");
+
+        assert_eq!(l.output(Format::Text, l.axiom()), "@0 0 -1 0.2 \n:Apex\nApex");
+    }
+
+    #[test]
+    fn render_header() {
+        let l = lsystem("#angle 30\n#width 0.1 2.5\n#color 1 769C23\n#axiom F\n");
+
+        assert_eq!(l.output(Format::Text, l.axiom()), "=angle 30 width 0.1 2.5 color 1 769c23\nF");
+        assert!(l.output(Format::Json, l.axiom())
+                .contains(r#""render":{"angle":30.0,"step":null,"width":[0.1,2.5],"shrink":null,"colors":{"1":"769c23"}}"#));
+    }
+}
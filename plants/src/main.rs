@@ -1,146 +1,92 @@
-use std::collections::HashMap;
-use std::env;
 use std::fs;
-use crate::symbolstring::SymbolString;
-
-mod pattern;
-mod iterate;
-mod parse_rules;
-mod lexer;
-mod ast;
-mod arith;
-mod ast_to_arith;
-mod bool_exp;
-mod ast_to_boolexp;
-mod symbol;
-mod symbolstring;
-mod iter_ctx;
-
-fn get_output_string(header: &String, contents: &SymbolString) -> String {
-    if header.len() > 0 {
-        format!("{}{}", header, contents.to_string())
-    } else {
-        contents.to_string()
-    }
+use std::process;
+use clap::Parser;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use plants::LSystem;
+use plants::output::Format;
+
+/// Derive an L-system from a rule file and write the resulting string
+#[derive(Parser)]
+#[command(name = "plants")]
+struct Args {
+    /// File containing the rules
+    rules: String,
+
+    /// Output file, intermediate steps are written to OUTPUT0, OUTPUT1...
+    output: String,
+
+    /// Number of derivation steps, overrides #niter
+    #[arg(short = 'n', long)]
+    iterations: Option<usize>,
+
+    /// Write the string at this time, with parameters declared with #growth interpolated
+    /// between two steps
+    #[arg(short, long, conflicts_with_all = ["iterations", "save_each_step"])]
+    time: Option<f32>,
+
+    /// Also write the string obtained after each step
+    #[arg(short, long)]
+    save_each_step: bool,
+
+    /// Format of the output file
+    #[arg(short = 'f', long, default_value = "text",
+          value_parser = PossibleValuesParser::new(["text", "json"])
+              .map(|s: String| Format::from(&s).unwrap()))]
+    output_format: Format,
+
+    /// Seed of stochastic rules, overrides #seed
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Number of threads used for rewriting
+    #[arg(short = 'j', long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    threads: u64,
 }
 
-fn main() -> Result<(), &'static str> {
-    let args: Vec<String> = env::args().collect();
-    let in_file = args[1].clone();                      // File containing rules
-    let mut path_split = in_file.rsplitn(2, "/");
-    path_split.next();
-    let file_folder = match path_split.next() {
-        Some(s) => s.to_string(),
-        _ => ".".to_string()
-    };
-    let out_file = args[2].clone(); //output file name
-    let save_iter = if args.len() > 3 {
-        args[3]
-            .parse::<usize>().expect("Invalid value for save_iter.")
-    } else {0};
-    let save_iter = if save_iter == 1 {true} else {false};
-
-    // Parse rules
-    let rule_str = fs::read_to_string(in_file)
-        .expect("Failed reading file.");
-    let mut ctx = parse_rules::parse_rules(&rule_str);
-
-    for r in &mut ctx.patterns {
-        r.rule_set(&"root".to_string());
+//write a file, exit on failure
+fn write(file: &str, contents: String) {
+    if let Err(e) = fs::write(file, contents) {
+        eprintln!("error: could not write {}: {}", file, e);
+        process::exit(1);
     }
+}
 
-    // Parse included rules
-    let mut shapesCtx : HashMap<String, iter_ctx::IterCtx> = HashMap::new();
-    let mut shapesRes : HashMap<String, SymbolString> = HashMap::new();
-
-    for (alias, file) in ctx.include.iter() {
-        println!("Importing file: {}/{}", file_folder, file);
-        let shape_rule_str = fs::read_to_string(format!("{}/{}", file_folder, file))
-            .expect("Failed reading file");
-        let mut shape_ctx = parse_rules::parse_rules(&shape_rule_str);
-        for pat in &mut shape_ctx.patterns {
-            pat.rule_set(alias);
-            for (obj, _) in &shape_ctx.objects {
-                pat.set_obj(obj);
-            }
-        }
-
-        shapesCtx.insert(alias.to_string(), shape_ctx);
+fn main() {
+    let args = Args::parse();
 
-        let shape_res = match SymbolString::from_string(shapesCtx
-            .get(alias).unwrap().axiom.as_str()) {
-            Ok(mut sym) => {sym.rule_set(alias); sym},
-            Err(e) => {
-                println!("Error parsing included rules : {}", e);
-                SymbolString{symbols : Vec::new()}
-            }
-        };
-        shapesRes.insert(alias.to_string(), shape_res);
-    }
-
-    //replace alias in "root" to the correct value
-    for pat in &mut ctx.patterns {
-        for (alias, value) in &shapesRes {
-            pat.replace(&alias, &value);
-        }
-        for (obj, _) in &ctx.objects {
-            pat.set_obj(obj);
+    // Parse rules and included rules
+    let mut lsystem = match LSystem::load(&args.rules, args.seed) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
         }
-    }
-
-    //println!("{:?}", rules);
-    //println!("{:?}", ignored);
-    let mut res = SymbolString::from_string(ctx.axiom.as_str())?;
-    res.rule_set(&"root".to_string());
-
-    //add root ctx to IterCtx map
-    shapesCtx.insert("root".to_string(), ctx);
-
-    //create the output header string
-    let mut tropism_str = String::from("@");
-    for (rule_set, ctx) in &shapesCtx {
-        tropism_str.push_str(&ctx.get_tropism_header());
-    }
-
-    let mut tropism_str = if tropism_str.len() > 1 {
-        tropism_str.push('\n');
-        tropism_str
-    } else {
-        String::new()
     };
-
-    let mut output_header = String::from("#");
-    for (rule_set, ctx) in &shapesCtx {
-        output_header.push_str(&ctx.get_object_header(rule_set, &file_folder));
+    for w in &lsystem.warnings {
+        eprintln!("warning: {}", w);
     }
-    let output_header = if output_header.len() > 1 {
-        output_header.push('\n');
-        output_header
-    } else {
-        String::new()
-    };
+    lsystem.set_threads(args.threads as usize);
+    println!("Seed: {}", lsystem.seed);
 
-    tropism_str.push_str(output_header.as_str());
+    if let Some(t) = args.time {
+        write(&args.output, lsystem.output(args.output_format, &lsystem.at(t)));
+        return;
+    }
 
     //iterate
-    let n_iter = shapesCtx["root"].n_iter;
-    for i in 0..n_iter {
-        // Iterate once on final res
-        res = iterate::iterate(
-            &res,
-            &mut shapesCtx
-        );
-        //println!("-----------------------------");
-        if save_iter {
-            let out_tmp = format!("{}{}", out_file, i.to_string());
+    let n_iter = args.iterations.unwrap_or(lsystem.n_iter());
+    let res = if args.save_each_step {
+        let mut res = lsystem.axiom().clone();
+        for (i, step) in lsystem.derive(n_iter).enumerate() {
+            let out_tmp = format!("{}{}", args.output, i);
             println!("Saving {}", out_tmp);
-            fs::write(out_tmp, get_output_string(&tropism_str, &res))
-                .expect("Unable to write to temporary output file.");
+            write(&out_tmp, lsystem.output(args.output_format, &step));
+            res = step;
         }
-    }
-
-    fs::write(out_file, get_output_string(&tropism_str, &res))
-        .expect("Unable to write to output file");
+        res
+    } else {
+        lsystem.derive_last(n_iter)
+    };
 
-    Ok(())
+    write(&args.output, lsystem.output(args.output_format, &res));
 }
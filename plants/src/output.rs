@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+use crate::symbolstring::SymbolString;
+
+//output formats of the derived string
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Text,//header lines followed by SymbolString::to_string
+    Json
+}
+
+impl Format {
+    pub fn from(s: &str) -> Option<Format> {
+        match s {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None
+        }
+    }
+}
+
+//rendering parameters of the root rule set, null if not given
+#[derive(Serialize, Debug, Default)]
+pub struct Render {
+    pub angle: Option<f32>,
+    pub step: Option<f32>,
+    pub width: Option<[f32; 2]>,//min, max
+    pub shrink: Option<f32>,
+    pub colors: BTreeMap<usize, String>//palette entries as rrggbb
+}
+
+//data written before the modules in the JSON output
+#[derive(Serialize, Debug)]
+pub struct Header {
+    pub tropism: Option<[f32; 4]>,//x, y, z, strength
+    pub objects: BTreeMap<String, String>,//name in the output -> file
+    pub rule_sets: Vec<String>,
+    pub render: Render
+}
+
+#[derive(Serialize)]
+struct Module<'a> {
+    sym: &'a str,
+    params: Vec<f32>,
+    rule_set: &'a str,
+    object: bool
+}
+
+#[derive(Serialize)]
+struct Output<'a> {
+    #[serde(flatten)]
+    header: &'a Header,
+    modules: Vec<Module<'a>>
+}
+
+pub fn to_json(header: &Header, contents: &SymbolString) -> String {
+    let modules = contents.symbols.iter()
+        .map(|s| Module{
+            sym: &s.sym,
+            params: s.get_vec(),
+            rule_set: &s.rule_set,
+            object: s.object
+        })
+        .collect();
+
+    serde_json::to_string(&Output{header, modules}).expect("Could not serialize output.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbol::Symbol;
+
+    #[test]
+    fn json_modules() {
+        let mut s = SymbolString::from_string("F(1,2.5)[X]").unwrap();
+        s.rule_set(&"root".to_string());
+        s.set_obj(&"X".to_string());
+        s.push(Symbol::new_with_values("Apex".to_string(), vec![3.0], "L".to_string()));
+        let mut objects = BTreeMap::new();
+        objects.insert("rootX".to_string(), "./x.obj".to_string());
+        let header = Header{tropism: None, objects, rule_sets: vec!["L".to_string(), "root".to_string()],
+                            render: Render::default()};
+
+        assert_eq!(to_json(&header, &s),
+                   "{\"tropism\":null,\"objects\":{\"rootX\":\"./x.obj\"},\"rule_sets\":[\"L\",\"root\"],\
+                   \"render\":{\"angle\":null,\"step\":null,\"width\":null,\"shrink\":null,\"colors\":{}},\
+                   \"modules\":[{\"sym\":\"F\",\"params\":[1.0,2.5],\"rule_set\":\"root\",\"object\":false},\
+                   {\"sym\":\"[\",\"params\":[],\"rule_set\":\"root\",\"object\":false},\
+                   {\"sym\":\"X\",\"params\":[],\"rule_set\":\"root\",\"object\":true},\
+                   {\"sym\":\"]\",\"params\":[],\"rule_set\":\"root\",\"object\":false},\
+                   {\"sym\":\"Apex\",\"params\":[3.0],\"rule_set\":\"L\",\"object\":false}]}");
+    }
+}
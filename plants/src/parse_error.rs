@@ -0,0 +1,57 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub file: String,
+    pub line: usize,//starting at 1
+    pub column: usize,//starting at 1
+    pub expected: Vec<String>,//expected tokens, empty if the error is not a syntax error
+    pub found: String,//token found, or error message if nothing was expected
+    pub source: String//content of the line
+}
+
+impl ParseError {
+    fn message(&self) -> String {
+        match self.expected.len() {
+            0 => self.found.clone(),
+            1 => format!("expected {}, found {}", self.expected[0], self.found),
+            _ => format!("expected one of {}, found {}", self.expected.join(", "), self.found)
+        }
+    }
+}
+
+//rustc-style diagnostic, with a caret under the bad token
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line_nb = self.line.to_string();
+        let margin = " ".repeat(line_nb.len());
+        writeln!(f, "error: {}", self.message())?;
+        writeln!(f, "{}--> {}:{}:{}", margin, self.file, self.line, self.column)?;
+        writeln!(f, "{} |", margin)?;
+        writeln!(f, "{} | {}", line_nb, self.source)?;
+        write!(f, "{} | {}^", margin, " ".repeat(self.column - 1))
+    }
+}
+
+//error while loading a grammar file or one of its includes
+#[derive(Debug)]
+pub enum LoadError {
+    Io(String, std::io::Error),//file, cause
+    Parse(String, Vec<ParseError>),//file, syntax errors
+    Axiom(String, &'static str)//file, cause
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(file, e) => write!(f, "error: could not read {}: {}", file, e),
+            LoadError::Parse(file, errors) => {
+                for e in errors {
+                    writeln!(f, "{}\n", e)?;
+                }
+                write!(f, "error: could not parse {} due to {} error(s)", file, errors.len())
+            },
+            LoadError::Axiom(file, e) => write!(f, "error: invalid axiom in {}: {}", file, e)
+        }
+    }
+}
@@ -2,14 +2,12 @@ use crate::pattern::{Pattern};
 use crate::ast::AstNode;
 use crate::lexer::{self, TokenType};
 use crate::symbolstring::SymbolString;
-use std::collections::HashMap;
-use std::collections::VecDeque;
-use std::fmt;
-use crate::symbol::Symbol;
+use std::collections::{BTreeMap, HashMap};
 use crate::iter_ctx::IterCtx;
 use crate::bool_exp::{BoolExp, BoolExpFactory};
-use crate::bool_exp::BoolExp::Bool;
-use crate::{ast_to_boolexp, bool_exp};
+use crate::parse_error::ParseError;
+use crate::growth::{Growth, GrowthFn};
+use crate::arith::ArithFunc;
 
 #[derive(Debug)]
 enum LineType {
@@ -35,14 +33,21 @@ struct SepLine {
     tok_types : Vec<TokType>
 }
 
-fn err(tok: &str, rule: &str, got: &lexer::Token) {
-    println!("{}: Expected token {}, got {:?}.", rule, tok, got.toktype);
+//record that tok was expected at index, the error is reported if the line cannot be parsed
+fn err(tok: &str, tokens: &lexer::Tokens, index: usize) {
+    tokens.expect(index, tok);
 }
 
 type AstRet = (Option<Box<AstNode>>, usize);
 
+//column of the token at index, for nodes that start there.
+//Nodes starting past the end of the line are never returned
+fn col(tokens: &lexer::Tokens, index: usize) -> usize {
+    tokens.get(index).map(|t| t.col).unwrap_or(0)
+}
+
 //lctx: pat '<'
-fn lctx(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
+fn lctx(tokens : &lexer::Tokens, index : usize) -> AstRet {
     let mut i = index;
     let result = match pat(tokens, i) {
         (Some(mut p), j) => {
@@ -52,12 +57,11 @@ fn lctx(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
         },
 
         (None, _) => {
-            //err("pat", "lctx", &tokens[i]);
             return (None, index);}
     };
 
     if  i >= tokens.len() || tokens[i].toktype != TokenType::Lsep {
-        //err("Lsep", "lctx", &tokens[i]);
+        err("`<`", tokens, i);
         return (None, index);
     }
 
@@ -66,10 +70,10 @@ fn lctx(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
 }
 
 //rctx: '>' pat
-fn rctx(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
+fn rctx(tokens : &lexer::Tokens, index : usize) -> AstRet {
     let mut i = index;
     if i >= tokens.len() || tokens[i].toktype != TokenType::Rsep {
-        //err("Rsep", "rctx", &tokens[i]);
+        err("`>`", tokens, i);
         return (None, index);
     }
 
@@ -78,7 +82,6 @@ fn rctx(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
         (Some(mut p), j) => {i = j; p.node_type = TokenType::Rctx;
         p},
         (None, _) => {
-            //err("pat", "rctx", &tokens[i]);
             return (None, index);}
     };
 
@@ -86,40 +89,43 @@ fn rctx(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
 }
 
 //p_word: char ['(' word ')']
-fn p_word(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
+fn p_word(tokens : &lexer::Tokens, index : usize) -> AstRet {
     if index >= tokens.len()
         || tokens[index].toktype != TokenType::Char && tokens[index].toktype != TokenType::Letter {
-        //err("char|letter", "p_word", &tokens[index]);
+        err("symbol", tokens, index);
         return (None, index);
     }
 
     let mut i = index;
     let mut result = AstNode{data: String::new(),
         children: vec!(Box::new(AstNode{data: tokens[index].val.clone(), children: Vec::new(),
-        node_type: TokenType::Word})),
-    node_type: TokenType::ParamWord};
+        node_type: TokenType::Word, col: tokens[index].col})),
+    node_type: TokenType::ParamWord, col: tokens[index].col};
     //create an ast node with first child being the word and second child the parameter
 
     i += 1;
     //check if there is a parameter
-    if i < tokens.len() && tokens[i].toktype == TokenType::Lpara {
+    if i >= tokens.len() || tokens[i].toktype != TokenType::Lpara {
+        err("`(`", tokens, i);
+    } else {
         match a_exp(tokens, i + 1) {
             (Some(mut w), j) => {
                 i = j;
                 let tmp = AstNode{
                     data: String::new(),
+                    col: w.col,
                     children: vec![w],
                     node_type: TokenType::Param
                 };
                 result.children.push(Box::new(tmp));
             },
             _ => {
-                //err("word", "p_word", &tokens[i]);
                 return (None, index);}
         };
 
         loop {
             if i >= tokens.len() || tokens[i].val != ','.to_string() {
+                err("`,`", tokens, i);
                 break;
             }
             i += 1;
@@ -129,20 +135,20 @@ fn p_word(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
                     i = j;
                     let tmp = AstNode{
                         data: String::new(),
+                        col: w.col,
                         children: vec![w],
                         node_type: TokenType::Param
                     };
                     result.children.push(Box::new(tmp));
                 },
                 _ => {
-                    //err("word", "p_word", &tokens[i]);
                     return (None, index);}
             };
         }
 
         //check closing separator
         if i >= tokens.len() || tokens[i].toktype != TokenType::Rpara {
-            //err("Rpara", "p_word", &tokens[i]);
+            err("`)`", tokens, i);
             return (None, index);
         }
         i += 1;
@@ -152,20 +158,21 @@ fn p_word(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
 }
 
 //prob: '@' number
-fn prob(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
+fn prob(tokens : &lexer::Tokens, index : usize) -> AstRet {
     let mut i = index;
     if i >= tokens.len() || tokens[i].toktype != TokenType::Psep {
-        //err("Psep", "prob", &tokens[i]);
+        err("`@`", tokens, i);
         return (None, index);
     }
 
     i += 1;
     if i >= tokens.len() || tokens[i].toktype != TokenType::Number {
-        //err("number", "prob", &tokens[i]);
+        err("number", tokens, i);
         return (None, index);
     }
 
-    let ret = AstNode{data: tokens[i].val.clone(), children: Vec::new(), node_type: TokenType::Prob};
+    let ret = AstNode{data: tokens[i].val.clone(), children: Vec::new(), node_type: TokenType::Prob,
+        col: tokens[i].col};
 
     i += 1;
 
@@ -173,7 +180,7 @@ fn prob(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
 }
 
 //word: letter+
-fn word(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
+fn word(tokens : &lexer::Tokens, index : usize) -> AstRet {
     let mut res_str = String::new();
 
     let mut i = index;
@@ -184,16 +191,16 @@ fn word(tokens : &VecDeque<lexer::Token>, index : usize) -> AstRet {
 
     if res_str.len() > 0 {
         (Some(Box::new(AstNode{data: res_str, children: Vec::new(),
-            node_type: TokenType::Word})), i)
+            node_type: TokenType::Word, col: tokens[index].col})), i)
     }
     else {
-        //err("Letter", "word", &tokens[i]);
+        err("letter", tokens, i);
         (None, index)
     }
 }
 
 //pattern: (p_word|Char)+
-pub fn pat(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
+pub fn pat(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
     let mut result = match p_word(tokens, i) {
         //create the root and add the child
@@ -202,7 +209,8 @@ pub fn pat(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
             AstNode{
             data: String::new(),
             children: vec![tree],
-            node_type: TokenType::Pat
+            node_type: TokenType::Pat,
+            col: tokens[index].col
         }},
         //check if we have a char(probably redundant)
         (None, _) => {
@@ -213,12 +221,12 @@ pub fn pat(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
                         AstNode {
                             data: tokens[i].val.clone(),
                             children: Vec::new(),
-                            node_type: TokenType::Char
+                            node_type: TokenType::Char,
+                            col: tokens[i].col
                         }
                     },
                     //invalid token, just return
                     _ => {
-                        //err("p_word", "pat", &tokens[i]);
                         return (None, index);
                     }
                 }
@@ -242,7 +250,8 @@ pub fn pat(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
                         let tmp = AstNode{
                             data: tokens[i].val.clone(),
                             children: Vec::new(),
-                            node_type: TokenType::Char
+                            node_type: TokenType::Char,
+                            col: tokens[i].col
                         };
                         result.children.push(Box::new(tmp));
                     },
@@ -259,7 +268,7 @@ pub fn pat(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
 }
 
 //param: any+ ws
-fn param(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
+fn param(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut res_str = String::new();
 
     let mut i = index;
@@ -270,19 +279,18 @@ fn param(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
 
     if res_str.len() > 0 {
         (Some(Box::new(AstNode{data: res_str, children: Vec::new(),
-            node_type: TokenType::Param})), i)
+            node_type: TokenType::Param, col: tokens[index].col})), i)
     }
     else {
-        //err("!Ws", "param", &tokens[i]);
         (None, index)
     }
 }
 
 //preproc: preproc_start word ws param+
-fn preproc(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
+fn preproc(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
     if i >= tokens.len() || tokens[i].toktype != TokenType::PreprocStart {
-        //err("PreprocStart", "preproc", &tokens[i]);
+        err("`#`", tokens, i);
         return (None, index);
     }
     i += 1;
@@ -293,7 +301,6 @@ fn preproc(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
             w
         }
         (None, _) => {
-            //err("word", "preproc", &tokens[i]);
             return (None, index);}
     };
 
@@ -314,47 +321,44 @@ fn preproc(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
     (Some(result), i)
 }
 
-fn not_tok(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering not_tok");
+fn not_tok(tokens: &lexer::Tokens, index: usize) -> AstRet {
     if index < tokens.len() && tokens[index].toktype == TokenType::Char {
         if tokens[index].val == '!'.to_string() {
             //return the token with the correct type
             (Some(Box::new(AstNode{data: tokens[index].val.clone(),
-                children: Vec::new(), node_type: TokenType::Not})), index + 1)
+                children: Vec::new(), node_type: TokenType::Not, col: tokens[index].col})), index + 1)
         }
         else {
-            //err("!", "not_tok", &tokens[index]);
+            err("`!`", tokens, index);
             (None, index)
         }
     }
     else {
-        //err("Char", "not_tok", &tokens[index]);
+        err("`!`", tokens, index);
         (None, index)
     }
 }
 
-fn or_tok(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering or_tok");
+fn or_tok(tokens: &lexer::Tokens, index: usize) -> AstRet {
     if index < tokens.len() && tokens[index].toktype == TokenType::Char {
         if tokens[index].val == '|'.to_string() {
             //return the token with the correct type
             (Some(Box::new(AstNode{data: tokens[index].val.clone(),
-                children: Vec::new(), node_type: TokenType::Or})), index + 1)
+                children: Vec::new(), node_type: TokenType::Or, col: tokens[index].col})), index + 1)
         }
         else {
-            //err("|", "or_tok", &tokens[index]);
+            err("`|`", tokens, index);
             (None, index)
         }
     }
     else {
-        //err("Char", "or_tok", &tokens[index]);
+        err("`|`", tokens, index);
         (None, index)
     }
 }
 
 //B_exp := '!' B_exp | B_exp '|' B_exp_and | B_exp_and
-fn cond(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering cond");
+fn cond(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
     let not = match not_tok(tokens, i) {
         (Some(_), j) => {
@@ -364,14 +368,14 @@ fn cond(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
         (None, _) => false
     };
 
-    let mut ret = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::Cond};
+    let mut ret = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::Cond,
+        col: col(tokens, i)};
     match cond_and(tokens, i) {
         (Some(and), j) => {
             i = j;
             ret.children.push(and);
         },
         (None, _) => {
-            //err("cond_and", "cond", &tokens[i]);
             return (None, index);}
     };
 
@@ -387,44 +391,42 @@ fn cond(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
                 ret.children.push(and);
             },
             (None, _) => {
-                //err("cond_and", "cond", &tokens[i]);
                 return (None, index);}
         };
     }
 
     if not {
         let tmp = AstNode{data: '!'.to_string(), children: vec![Box::new(ret)],
-            node_type: TokenType::Not};
+            node_type: TokenType::Not, col: tokens[index].col};
         (Some(Box::new(tmp)), i)
     } else {
         (Some(Box::new(ret)), i)
     }
 }
 
-fn and_tok(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering and_tok");
+fn and_tok(tokens: &lexer::Tokens, index: usize) -> AstRet {
     if index < tokens.len() && tokens[index].toktype == TokenType::Char {
         if tokens[index].val == '&'.to_string() {
             //return the token with the correct type
             (Some(Box::new(AstNode{data: tokens[index].val.clone(),
-                children: Vec::new(), node_type: TokenType::And})), index + 1)
+                children: Vec::new(), node_type: TokenType::And, col: tokens[index].col})), index + 1)
         }
         else {
-            //err("&", "and_tok", &tokens[index]);
+            err("`&`", tokens, index);
             (None, index)
         }
     }
     else {
-        //err("Char", "and_tok", &tokens[index]);
+        err("`&`", tokens, index);
         (None, index)
     }
 }
 
 //B_exp_and := B_exp_and '&' B_para | B_para
-fn cond_and(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering cond_and");
+fn cond_and(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
-    let mut ret = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::CondAnd};
+    let mut ret = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::CondAnd,
+        col: col(tokens, index)};
 
     match cond_para(tokens, i) {
         (Some(para), j) => {
@@ -432,7 +434,6 @@ fn cond_and(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
             ret.children.push(para);
         },
         (None, _) => {
-            //err("cond_para", "cond_and", &tokens[i]);
             return (None, index);}
     };
 
@@ -448,7 +449,6 @@ fn cond_and(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
                 ret.children.push(para);
             },
             (None, _) => {
-                //err("cond_para", "cond_and", &tokens[i]);
                 return (None, index);}
         };
     }
@@ -457,8 +457,7 @@ fn cond_and(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
 }
 
 //B_para := '(' B_exp ')' | Bool
-fn cond_para(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering cond_para");
+fn cond_para(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
     if i < tokens.len() && tokens[i].toktype == TokenType::Lpara {
         let ret = match cond(tokens, i + 1) {
@@ -467,7 +466,6 @@ fn cond_para(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
                 cond
             },
             (None, _) => {
-                //err("cond", "cond_para", &tokens[i]);
                 //potentially a_para
                 let (b, j) = cond_bool(tokens, index);
                 return (b, j);
@@ -475,7 +473,7 @@ fn cond_para(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
         };
 
         if i >= tokens.len() || tokens[i].toktype != TokenType::Rpara {
-            //err("Rpara", "cond_para", &tokens[i]);
+            err("`)`", tokens, i);
             (None, index)
         } else {
             (Some(ret), i + 1)
@@ -487,7 +485,6 @@ fn cond_para(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
                 (Some(b), j)
             },
             (None, _) => {
-                //err("cond_bool", "cond_para", &tokens[i]);
                 (None, index)
             }
         }
@@ -495,8 +492,7 @@ fn cond_para(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
 }
 
 //Bool := 'true' | 'false' | Comp_exp
-fn cond_bool(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering cond_bool");
+fn cond_bool(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
     let ret = match word(tokens, i) {
         (Some(mut w), j)
@@ -512,7 +508,6 @@ fn cond_bool(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
                     exp
                 },
                 (None, _) => {
-                    //err("comp_exp", "cond_bool", &tokens[i]);
                     return (None, index);}
             }
         }
@@ -522,23 +517,22 @@ fn cond_bool(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
 }
 
 //Comp_exp := A_exp Comp_op A_exp
-fn comp_exp(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering comp_exp");
+fn comp_exp(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
-    let mut res = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::CompExp};
+    let mut res = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::CompExp,
+        col: col(tokens, index)};
     match a_exp(tokens, i) {
         (Some(exp), j) => {i = j; res.children.push(exp);},
         (None, _) => {
-            //err("a_exp", "comp_exp", &tokens[i]);
             return (None, index);}
     }
 
-    if i < tokens.len() && tokens[i].toktype == TokenType::CompOp
-        || tokens[i].toktype == TokenType::Rsep || tokens[i].toktype == TokenType::Lsep {
+    if i < tokens.len() && (tokens[i].toktype == TokenType::CompOp
+        || tokens[i].toktype == TokenType::Rsep || tokens[i].toktype == TokenType::Lsep) {
         res.data = tokens[i].val.clone();
     }
     else {
-        //err("CompOp", "comp_exp", &tokens[i]);
+        err("comparison operator", tokens, i);
         return (None, index)
     }
 
@@ -547,7 +541,6 @@ fn comp_exp(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
     match a_exp(tokens, i) {
         (Some(exp), j) => {i = j; res.children.push(exp);},
         (None, _) => {
-            //err("a_exp", "comp_exp", &tokens[i]);
             return (None, index);
         }
     }
@@ -558,34 +551,32 @@ fn comp_exp(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
 //Comp_op := '=' | '!=' | '>' | '<' | '>=' | '<='
 //in lexer
 
-fn add_tok(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering add_tok");
+fn add_tok(tokens: &lexer::Tokens, index: usize) -> AstRet {
     if index < tokens.len() && tokens[index].toktype == TokenType::Char {
         if tokens[index].val == '+'.to_string() || tokens[index].val == '-'.to_string() {
             (Some(Box::new(AstNode{data: tokens[index].val.clone(),
-            children: Vec::new(), node_type: TokenType::Add})), index + 1)
+            children: Vec::new(), node_type: TokenType::Add, col: tokens[index].col})), index + 1)
         }
         else {
-            //err("+|-", "add_tok", &tokens[index]);
+            err("`+`", tokens, index);
             (None, index)
         }
     }
     else {
-        //err("Char", "add_tok", &tokens[index]);
+        err("`+`", tokens, index);
         (None, index)
     }
 }
 
-//A_exp := A_exp '+' A_exp_mul | A_exp_mul
-fn a_exp(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering a_exp");
+//A_exp := A_exp ('+'|'-') A_exp_mul | A_exp_mul
+fn a_exp(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
-    let mut ret = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::Aexp};
+    let mut ret = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::Aexp,
+        col: col(tokens, index)};
 
     match a_exp_mul(tokens, i) {
         (Some(mul), j) => {i = j; ret.children.push(mul);},
         (None, _) => {
-            //err("a_exp_mul", "a_exp", &tokens[i]);
             return (None, index);
         }
     };
@@ -602,7 +593,6 @@ fn a_exp(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
         match a_exp_mul(tokens, i) {
             (Some(mul), j) => {i = j; ret.children.push(mul);},
             (None, _) => {
-                //err("a_exp_mul", "a_exp", &tokens[i]);
                 return (None, index);
             }
         };
@@ -611,37 +601,36 @@ fn a_exp(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
     (Some(Box::new(ret)), i)
 }
 
-//test if token i  is a multiplication or division token
-//differenciate between the two operators when creating the ast for evaluating the condition
-fn mul_tok(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering mul_tok");
+//test if token i  is a multiplication, division or modulo token
+//differenciate between the operators when creating the ast for evaluating the condition
+fn mul_tok(tokens: &lexer::Tokens, index: usize) -> AstRet {
     if index < tokens.len() && tokens[index].toktype == TokenType::Char {
-        if tokens[index].val == '/'.to_string() || tokens[index].val == '*'.to_string() {
+        if tokens[index].val == '/'.to_string() || tokens[index].val == '*'.to_string()
+            || tokens[index].val == '%'.to_string() {
             //return the token with the correct type
             (Some(Box::new(AstNode{data: tokens[index].val.clone(),
-            children: Vec::new(), node_type: TokenType::Mul})), index + 1)
+            children: Vec::new(), node_type: TokenType::Mul, col: tokens[index].col})), index + 1)
         }
         else {
-            //err("/|*", "mul_tok", &tokens[index]);
+            err("`*`", tokens, index);
             (None, index)
         }
     }
     else {
-        //err("Char", "mul_tok", &tokens[index]);
+        err("`*`", tokens, index);
         (None, index)
     }
 }
 
-//A_exp_mul := A_exp_mul '*' A_para | A_para
-fn a_exp_mul(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering a_exp_mul");
+//A_exp_mul := A_exp_mul '*' A_unary | A_unary
+fn a_exp_mul(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
-    let mut ret = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::AexpMul};
+    let mut ret = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::AexpMul,
+        col: col(tokens, index)};
 
-    match a_para(tokens, i) {
+    match a_unary(tokens, i) {
         (Some(para), j) => {i = j; ret.children.push(para);},
         (None, _) => {
-            //err("a_para", "a_exp_mul", &tokens[i]);
             return (None, index);
         }
     };
@@ -655,10 +644,9 @@ fn a_exp_mul(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
             (None, _) => {break;}
         };
 
-        match a_para(tokens, i) {
+        match a_unary(tokens, i) {
             (Some(para), j) => {i = j; ret.children.push(para);},
             (None, _) => {
-                //err("a_para", "a_exp_mul", &tokens[i]);
                 return (None, index);
             }
         };
@@ -667,35 +655,78 @@ fn a_exp_mul(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
     (Some(Box::new(ret)), i)
 }
 
-//A_para := '(' A_exp ')' | Num
-fn a_para(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
-    //println!("Entering a_para");
+//A_unary := '-' A_unary | A_pow
+fn a_unary(tokens: &lexer::Tokens, index: usize) -> AstRet {
+    if index < tokens.len() && tokens[index].toktype == TokenType::Char
+        && tokens[index].val == "-" {
+        match a_unary(tokens, index + 1) {
+            (Some(e), j) => (Some(Box::new(AstNode{data: String::new(), children: vec![e],
+                node_type: TokenType::Aneg, col: tokens[index].col})), j),
+            (None, _) => (None, index)
+        }
+    }
+    else {
+        err("`-`", tokens, index);
+        a_pow(tokens, index)
+    }
+}
+
+//A_pow := A_para '^' A_unary | A_para
+//right associative, and binds tighter than the negation: -x^2 = -(x^2)
+fn a_pow(tokens: &lexer::Tokens, index: usize) -> AstRet {
+    let (base, i) = match a_para(tokens, index) {
+        (Some(e), j) => (e, j),
+        (None, _) => {
+            return (None, index);
+        }
+    };
+
+    if i < tokens.len() && tokens[i].toktype == TokenType::Char && tokens[i].val == "^" {
+        match a_unary(tokens, i + 1) {
+            (Some(exponent), j) => (Some(Box::new(AstNode{data: String::new(),
+                children: vec![base, exponent], node_type: TokenType::Apow, col: tokens[index].col})), j),
+            (None, _) => (None, index)
+        }
+    }
+    else {
+        err("`^`", tokens, i);
+        (Some(base), i)
+    }
+}
+
+//A_para := '(' A_exp ')' | Func | Num
+fn a_para(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
-    if i < tokens.len() && tokens[i].toktype != TokenType::Lpara {
-        match a_num(tokens, i) {
-            (Some(mut n), j) => {n.node_type = TokenType::Apara; (Some(n), j)},
-            (None, _) => {
-                //err("a_num", "a_para", &tokens[i]);
-                (None, index)
+    if i >= tokens.len() {
+        err("number", tokens, i);
+        (None, index)
+    }
+    else if tokens[i].toktype != TokenType::Lpara {
+        match a_func(tokens, i) {
+            (Some(f), j) => (Some(f), j),
+            (None, _) => match a_num(tokens, i) {
+                (Some(mut n), j) => {n.node_type = TokenType::Apara; (Some(n), j)},
+                (None, _) => {
+                    (None, index)
+                }
             }
         }
     }
     else {
         i += 1;
         let tmp = match a_exp(tokens, i) {
-            (Some(mut e), j) => {
+            (Some(e), j) => {
                 i = j;
-                e.node_type = TokenType::Apara;
-                Some(e)
+                Some(Box::new(AstNode{data: String::new(), children: vec![e],
+                    node_type: TokenType::Apara, col: tokens[index].col}))
             },
             (None, _) => {
-                //err("a_exp", "a_para", &tokens[i]);
                 None
             }
         };
 
         if i >= tokens.len() || tokens[i].toktype != TokenType::Rpara {
-            //err("Rpara", "a_para", &tokens[i]);
+            err("`)`", tokens, i);
             (None, index)
         }
         else {
@@ -704,18 +735,51 @@ fn a_para(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
     }
 }
 
+//Func := Word '(' A_exp (',' A_exp)* ')'
+//the function name is checked when converting the ast
+fn a_func(tokens: &lexer::Tokens, index: usize) -> AstRet {
+    let (mut res, mut i) = match word(tokens, index) {
+        (Some(w), j) if j < tokens.len() && tokens[j].toktype == TokenType::Lpara => (w, j + 1),
+        _ => {
+            return (None, index);
+        }
+    };
+    res.node_type = TokenType::Afunc;
+
+    loop {
+        match a_exp(tokens, i) {
+            (Some(e), j) => {i = j; res.children.push(e);},
+            (None, _) => {
+                return (None, index);
+            }
+        };
+
+        if i < tokens.len() && tokens[i].toktype == TokenType::Char && tokens[i].val == "," {
+            i += 1;
+        }
+        else if i < tokens.len() && tokens[i].toktype == TokenType::Rpara {
+            return (Some(res), i + 1);
+        }
+        else {
+            err("`,`", tokens, i);
+            err("`)`", tokens, i);
+            return (None, index);
+        }
+    }
+}
+
 //Num := 0|...|9 | 0 Num | ... | 9 Num | Var
-fn a_num(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
+fn a_num(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let i = index;
     if i < tokens.len() && tokens[i].toktype == TokenType::Number {
         (Some(Box::new(AstNode{data: tokens[i].val.clone(), children: Vec::new(),
-        node_type: TokenType::Anum})), i + 1)
+        node_type: TokenType::Anum, col: tokens[i].col})), i + 1)
     }
     else {
         match word(tokens, i) {
             (Some(mut w), j) => {w.node_type = TokenType::Anum; (Some(w), j)},
             (None, _) => {
-                //err("word|Number", "a_num", &tokens[i]);
+                err("number", tokens, i);
                 (None, index)
             }
         }
@@ -723,27 +787,29 @@ fn a_num(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
 }
 
 //rule: [lctx] p_word [rctx] [cond] [prob] patsep pat
-fn rule(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
+fn rule(tokens: &lexer::Tokens, index: usize) -> AstRet {
     let mut i = index;
-    let mut result = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::Rule};
+    let mut result = AstNode{data: String::new(), children: Vec::new(), node_type: TokenType::Rule,
+        col: col(tokens, index)};
 
+    //the left context is optional, forget its errors if there is none
+    let failure = tokens.failure();
     match lctx(tokens, i) {
         (Some(ctx), j) => {
             i = j;
             result.children.push(ctx);
         },
-        _ => {}
+        _ => {tokens.restore(failure);}
     };
 
-    //can only replace one symbol, thus use p_word
-    match p_word(tokens, i) {
+    //the predecessor can be several symbols, as the replacement
+    match pat(tokens, i) {
         (Some(mut p), j) => {
             i = j;
             p.node_type = TokenType::Pred;
             result.children.push(p);
         },
         _ => {
-            //err("pat", "rule", &tokens[i]);
             return (None, index);
         }
     };
@@ -756,7 +822,9 @@ fn rule(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
         _ => {}
     };
 
-    if tokens[i].toktype == TokenType::Condsep {
+    if i >= tokens.len() || tokens[i].toktype != TokenType::Condsep {
+        err("`:`", tokens, i);
+    } else {
         match cond(tokens, i + 1) {
             (Some(c), j) => {
                 i = j;
@@ -775,7 +843,7 @@ fn rule(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
     };
 
     if i >= tokens.len() || tokens[i].toktype != TokenType::Patsep {
-        //err("PatSep", "rule", &tokens[i]);
+        err("`->`", tokens, i);
         return (None, index);
     }
      i += 1;
@@ -787,7 +855,6 @@ fn rule(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
             result.children.push(p);
         },
         _ => {
-            //err("pat", "rule", &tokens[i]);
             return (None, index);
         }
     };
@@ -795,128 +862,164 @@ fn rule(tokens: &VecDeque<lexer::Token>, index: usize) -> AstRet {
     (Some(Box::new(result)), i)
 }
 
+fn error(file: &str, line_nb: usize, line: &str, column: usize,
+         expected: Vec<String>, found: String) -> ParseError {
+    ParseError{file: file.to_string(), line: line_nb, column, expected, found,
+        source: line.to_string()}
+}
+
+//error at token index, with the tokens expected there
+fn syntax_error(file: &str, line_nb: usize, line: &str, tokens: &lexer::Tokens,
+                index: usize, expected: Vec<String>) -> ParseError {
+    if index < tokens.len() {
+        error(file, line_nb, line, tokens[index].col + 1, expected,
+              format!("`{}`", tokens[index].val))
+    } else {
+        error(file, line_nb, line, line.chars().count() + 1, expected,
+              "end of line".to_string())
+    }
+}
+
 //preproc | rule
-fn parse(s : &str) -> Option<Box<AstNode>> {
-    let tokens = lexer::lexer(&s);
-    let mut result = Box::new(AstNode{data: String::new(), children: Vec::new(),
-        node_type: TokenType::Rule});
-    match preproc(&tokens, 0) {
-        (Some(p), _) => Some(p),
-        _ => {
-            match rule(&tokens, 0) {
-                (Some(r), _) => Some(r),
-                _ => {
-                    //err("preproc|rule", "parse", &tokens[0]);
-                    None
-                }
-            }
+//return None for blank lines
+fn parse(file: &str, line_nb: usize, line: &str, modules: &[String])
+    -> Result<Option<Box<AstNode>>, ParseError> {
+    let line = line.trim_end();
+    let tokens = lexer::lexer(line, modules);
+    //skip indentation
+    let start = tokens.iter().take_while(|t| t.toktype == TokenType::Ws).count();
+    if start >= tokens.len() {
+        return Ok(None);
+    }
+
+    let (ast, j) = match preproc(&tokens, start) {
+        (Some(p), j) => (Some(p), j),
+        _ => rule(&tokens, start)
+    };
+
+    //report the error at the furthest position the parser reached
+    let (index, expected) = tokens.failure();
+    match ast {
+        Some(ast) if j >= tokens.len() => Ok(Some(ast)),
+        Some(_) if index < j => {
+            Err(syntax_error(file, line_nb, line, &tokens, j, vec!["end of line".to_string()]))
+        },
+        _ => Err(syntax_error(file, line_nb, line, &tokens, index, expected))
+    }
+}
+
+//column, expected tokens and token found
+type RuleError = (usize, Vec<String>, String);
+
+//function calls with an unknown name or a wrong number of arguments,
+//checked before creating the rule to report them at their position
+fn check_funcs(ast: &AstNode) -> Result<(), RuleError> {
+    if ast.node_type == TokenType::Afunc {
+        match ArithFunc::n_args(&ast.data) {
+            None => {
+                return Err((ast.col, vec!["function".to_string()], format!("`{}`", ast.data)));
+            },
+            Some(n) if n != ast.children.len() => {
+                return Err((ast.col, vec![format!("{} argument(s) for `{}`", n, ast.data)],
+                            ast.children.len().to_string()));
+            },
+            _ => {}
         }
     }
+    ast.children.iter().try_for_each(|c| check_funcs(c))
 }
 
 fn create_rule(ast: Box<AstNode>) -> Result<Pattern, &'static str> {
     let mut left : Option<SymbolString> = None;
     let mut right : Option<SymbolString> = None;
     let mut p : f32 = 1.0;
-    let mut pattern : Symbol = Symbol{
-        sym: 'a',
-        var_names: Vec::new(),
-        params: Vec::new(),
-        rule_set: String::new(),
-        object: false
-    };
+    let mut pattern : SymbolString = SymbolString{ symbols: Vec::new() };
     let mut replacement : SymbolString = SymbolString{ symbols: Vec::new() };
     let mut has_pattern : bool = false;
     let mut has_replacement : bool = false;
     let mut cond : Option<Box<BoolExp>> = None;
 
     for tok in ast.children.iter() {
-        //tok.print(0);
         match tok.node_type {
             TokenType::Rctx => {
-                //println!("Rctx");
                 right = Some(SymbolString::from_ast(tok, String::new())?);
             },
             TokenType::Lctx => {
-                //println!("Lctx");
                 left = Some(SymbolString::from_ast(tok, String::new())?);
             },
             TokenType::Prob => {
-                //println!("Prob");
-                p = tok.data.parse::<f32>().unwrap()
+                p = tok.data.parse::<f32>().map_err(|_| "Invalid probability.")?
             },
             TokenType::Pred => {
-                //println!("Pred");
-                pattern = Symbol::from_ast(tok)?;
+                pattern = SymbolString::from_ast(tok, String::new())?;
                 has_pattern = true;
             },
             TokenType::Replacement => {
-                //println!("Replacement");
                 replacement = SymbolString::from_ast(tok, String::new())?;
                 has_replacement = true;
             },
             TokenType::Cond => {
-                cond = Some(BoolExp::create_from(tok)
-                                .unwrap_or(bool_exp::Bool::new(false)));
+                cond = Some(BoolExp::create_from(tok)?);
             },
-            _ => {
-                //println!("Unknown: {:?}", tok.node_type);
-            }
+            _ => {}
         };
     }
 
     if !has_pattern || !has_replacement {
        Err("Rule is missing a pattern or replacement.")
     } else {
-        //println!("{}", pattern.to_string());
-        //println!("{}", replacement.to_string());
         Ok(Pattern::new(pattern, replacement, p, left, right, cond))
     }
 }
 
-fn get_param_value(ast: &Box<AstNode>, i: usize) -> String {
+fn get_param_value(ast: &AstNode, i: usize) -> Result<String, PreprocError> {
     if i >= ast.children.len() {
-        "ERROR".to_string()
+        Err(("value", "end of line".to_string()))
     } else {
-        ast.children[i].data.clone()
+        Ok(ast.children[i].data.clone())
     }
 }
 
-fn get_define_value(ast: Box<AstNode>, i: usize) -> Vec<String> {
-    let mut res = Vec::new();
-    if i + 1 >= ast.children.len() {
-        res.push("ERROR".to_string());
-        res.push("ERROR".to_string());
-    } else {
-        res.push(ast.children[i].data.clone());
-        res.push(ast.children[i+1].data.clone());
-    }
-    res
+fn get_define_value(ast: Box<AstNode>, i: usize) -> Result<Vec<String>, PreprocError> {
+    Ok(vec![get_param_value(&ast, i)?, get_param_value(&ast, i + 1)?])
+}
+
+fn parse_value<T: std::str::FromStr>(value: String, expected: &'static str)
+    -> Result<T, PreprocError> {
+    value.parse::<T>().map_err(|_| (expected, value))
 }
 
-fn read_preproc(ast: Box<AstNode>, ctx: &mut IterCtx) {
+//expected value and value found
+type PreprocError = (&'static str, String);
+
+fn read_preproc(ast: Box<AstNode>, ctx: &mut IterCtx) -> Result<(), PreprocError> {
     if ast.node_type != TokenType::Preproc {
-        return;//invalid node type
+        return Ok(());//invalid node type
     }
 
     match ast.data.as_str() {
-        "ignore" => {ctx.ignored = get_param_value(&ast, 0);},
-        "axiom" => {ctx.axiom = get_param_value(&ast, 0);},
-        "niter" => {ctx.n_iter = get_param_value(&ast, 0).parse::<usize>()
-            .expect("Invalid parameter formating for niter command.");},
+        "ignore" => {
+            //multi-character module names are separated by spaces
+            get_param_value(&ast, 0)?;
+            ctx.ignored = ast.children.iter().map(|c| c.data.clone())
+                .collect::<Vec<String>>().join(" ");
+        },
+        "axiom" => {ctx.axiom = get_param_value(&ast, 0)?;},
+        "niter" => {ctx.n_iter = parse_value(get_param_value(&ast, 0)?, "integer")?;},
         "define" => {
-            let def = get_define_value(ast, 0);
-            ctx.define.insert(def[0].clone(), def[1].clone().parse().unwrap());
+            let def = get_define_value(ast, 0)?;
+            let value = parse_value(def[1].clone(), "number")?;
+            ctx.define.insert(def[0].clone(), value);
         },
         "include" => {
-            let alias_file = get_define_value(ast, 0);
+            let alias_file = get_define_value(ast, 0)?;
             let alias = alias_file[0].clone();
             let file = alias_file[1].clone();
             ctx.include.insert(alias, file);
         },
         //object/import preproc
         "object" => {
-            let obj_alias = get_define_value(ast, 0);
+            let obj_alias = get_define_value(ast, 0)?;
             let alias = obj_alias[0].clone();
             let file = obj_alias[1].clone();
             ctx.objects.insert(alias, file);
@@ -924,18 +1027,142 @@ fn read_preproc(ast: Box<AstNode>, ctx: &mut IterCtx) {
         "tropism" => {
             let mut params = Vec::new();
             for i in 0..4 {
-                let cur = get_param_value(&ast, i);
+                let cur = get_param_value(&ast, i)?;
+                parse_value::<f32>(cur.clone(), "number")?;
                 params.push(cur);
             }
             ctx.tropism = params;
         },
-        _ => {}
+        "module" => {
+            get_param_value(&ast, 0)?;
+            for name in &ast.children {
+                let mut chars = name.data.chars();
+                let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !valid {
+                    return Err(("module name", name.data.clone()));
+                }
+                if !ctx.modules.contains(&name.data) {
+                    ctx.modules.push(name.data.clone());
+                }
+            }
+        },
+        //rendering parameters
+        "angle" => {ctx.angle = Some(parse_value(get_param_value(&ast, 0)?, "number")?);},
+        "step" => {ctx.step = Some(parse_value(get_param_value(&ast, 0)?, "number")?);},
+        "width" => {
+            let min = parse_value(get_param_value(&ast, 0)?, "number")?;
+            let max = parse_value(get_param_value(&ast, 1)?, "number")?;
+            ctx.width = Some((min, max));
+        },
+        "shrink" => {
+            let value = get_param_value(&ast, 0)?;
+            let shrink: f32 = parse_value(value.clone(), "number")?;
+            if shrink <= 0.0 || shrink > 1.0 {
+                return Err(("number in ]0, 1]", value));
+            }
+            ctx.shrink = Some(shrink);
+        },
+        "color" => {
+            let index = parse_value(get_param_value(&ast, 0)?, "integer")?;
+            let color = get_param_value(&ast, 1)?;
+            if color.len() != 6 || !color.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(("color as rrggbb", color));
+            }
+            ctx.colors.insert(index, color.to_lowercase());
+        },
+        "growth" => {
+            let sym = get_param_value(&ast, 0)?;
+            let param = parse_value(get_param_value(&ast, 1)?, "integer")?;
+            let function = match ast.children.get(2) {
+                Some(f) => GrowthFn::from(&f.data).ok_or(("`linear` or `smooth`", f.data.clone()))?,
+                None => GrowthFn::Linear
+            };
+            ctx.growth.push(Growth{sym, param, function});
+        },
+        "seed" => {ctx.seed = Some(parse_value(get_param_value(&ast, 0)?, "integer")?);},
+        _ => {ctx.warnings.push(format!("unknown directive #{}", ast.data));}
     };
+
+    Ok(())
+}
+
+//key identifying the predecessor, contexts and condition of a rule
+fn group_key(ast: &AstNode) -> String {
+    let parts: Vec<String> = ast.children.iter()
+        .filter(|c| c.node_type != TokenType::Prob && c.node_type != TokenType::Replacement)
+        .map(|c| c.key())
+        .collect();
+    parts.join(" ")
+}
+
+//group rules with the same key together for stochastic selection.
+//Rules without probability in a stochastic group share the remaining weight.
+//Returns a warning for each group whose probabilities do not sum to 1
+fn set_groups(patterns: &mut [Pattern], keys: &[String], explicit_p: &[bool]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut groups: Vec<&String> = Vec::new();
+    for (i, key) in keys.iter().enumerate() {
+        patterns[i].group = match groups.iter().position(|g| *g == key) {
+            Some(g) => g,
+            None => {
+                groups.push(key);
+                groups.len() - 1
+            }
+        };
+    }
+
+    for g in 0..groups.len() {
+        let members: Vec<usize> = (0..patterns.len())
+            .filter(|i| patterns[*i].group == g)
+            .collect();
+        if !members.iter().any(|i| explicit_p[*i]) {
+            continue;//deterministic rules
+        }
+
+        let explicit_sum: f32 = members.iter()
+            .filter(|i| explicit_p[**i])
+            .map(|i| patterns[*i].p)
+            .sum();
+        let implicit: Vec<usize> = members.iter().cloned()
+            .filter(|i| !explicit_p[*i])
+            .collect();
+        for i in &implicit {
+            patterns[*i].p = (1.0 - explicit_sum).max(0.0) / implicit.len() as f32;
+        }
+
+        let sum: f32 = members.iter().map(|i| patterns[*i].p).sum();
+        if (sum - 1.0).abs() > 0.001 {
+            warnings.push(format!("probabilities of rules {} sum to {}, they will be normalized",
+                     members.iter().map(|i| patterns[*i].pattern.to_string())
+                         .collect::<Vec<String>>().join(", "),
+                     sum));
+        }
+    }
+    warnings
+}
+
+// Entries of #ignore are declared module names or single characters written next to
+// each other. Split the latter so that each ignored symbol is a separate entry.
+fn split_ignored(ignored : &str, modules : &[String]) -> String {
+    let mut entries = Vec::new();
+    for e in ignored.split_whitespace() {
+        if modules.iter().any(|m| m == e) {
+            entries.push(e.to_string());
+        } else {
+            entries.extend(e.chars().map(|c| c.to_string()));
+        }
+    }
+    entries.join(" ")
 }
 
 // Instantiate Pattern objects from a string.
-pub fn parse_rules(data : &str) -> IterCtx {
+// file is only used to report errors, which are collected over the whole string.
+pub fn parse_rules(file : &str, data : &str) -> Result<IterCtx, Vec<ParseError>> {
     let mut result = Vec::new();
+    let mut keys = Vec::new();//group key of each rule
+    let mut explicit_p = Vec::new();//true if the rule has a '@' probability
+    let mut errors = Vec::new();
     let mut ctx : IterCtx = IterCtx{
                                 ignored  : String::new(),
                                 axiom: String::new(),
@@ -944,38 +1171,185 @@ pub fn parse_rules(data : &str) -> IterCtx {
                                 include  : HashMap::new(),
                                 patterns : Vec::new(),
                                 objects  : HashMap::new(),
-                                tropism  : Vec::new()
+                                tropism  : Vec::new(),
+                                seed     : None,
+                                modules  : Vec::new(),
+                                angle    : None,
+                                step     : None,
+                                width    : None,
+                                shrink   : None,
+                                colors   : BTreeMap::new(),
+                                growth   : Vec::new(),
+                                warnings : Vec::new()
     };
 
+    //module names are read first, so that rules can use them before their declaration.
+    //Invalid declarations are reported below
     for l in data.lines() {
-        //println!("{}", l);
-        //println!("{:?}", tokenize(l));
-        let rule_ast = parse(l);
-        let rule_ast = match rule_ast {
-            Some(ast) => {
-                //ast.print(0);
-                //ast
-                match ast.node_type {
-                    TokenType::Rule => {
-                        match create_rule(ast) {
-                            Ok(r) => {result.push(r);},
-                            Err(e) => {
-                                println!("Error while creating rule: {}", e);
-                            }
-                    }},
-                    TokenType::Preproc => {read_preproc(ast, &mut ctx);},
-                    _ => {}
-                };
-            },
-            None => {
-                println!("Invalid rule: {}", l);
-                //return (Vec::new(), "".to_string());
+        if let Ok(Some(ast)) = parse(file, 0, l, &[]) {
+            if ast.node_type == TokenType::Preproc && ast.data == "module" {
+                let _ = read_preproc(ast, &mut ctx);
             }
+        }
+    }
+
+    for (n, l) in data.lines().enumerate() {
+        let ast = match parse(file, n + 1, l, &ctx.modules) {
+            Ok(Some(ast)) => ast,
+            Ok(None) => {continue;},
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        match ast.node_type {
+            TokenType::Rule => {
+                let key = group_key(&ast);
+                let has_prob = ast.children.iter()
+                    .any(|c| c.node_type == TokenType::Prob);
+                let col = ast.col;
+                let rule = check_funcs(&ast)
+                    .and_then(|_| create_rule(ast).map_err(|e| (col, Vec::new(), e.to_string())));
+                match rule {
+                    Ok(r) => {
+                        result.push(r);
+                        keys.push(key);
+                        explicit_p.push(has_prob);
+                    },
+                    Err((col, expected, found)) => {
+                        errors.push(error(file, n + 1, l, col + 1, expected, found));
+                    }
+            }},
+            TokenType::Preproc => {
+                if let Err((expected, found)) = read_preproc(ast, &mut ctx) {
+                    let column = if found == "end of line" {
+                        l.trim_end().chars().count() + 1
+                    } else {
+                        l.find(&found).map(|c| l[..c].chars().count() + 1).unwrap_or(1)
+                    };
+                    let found = if found == "end of line" {found} else {format!("`{}`", found)};
+                    errors.push(error(file, n + 1, l, column, vec![expected.to_string()], found));
+                }
+            },
+            _ => {}
         };
     }
 
-    //println!("{:?}", ctx);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    ctx.ignored = split_ignored(&ctx.ignored, &ctx.modules);
+    let warnings = set_groups(&mut result, &keys, &explicit_p);
+    ctx.warnings.extend(warnings);
     result.sort_by(|a, b| a.cmp_pat(b));
     ctx.patterns = result;
-    ctx
+    Ok(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(data : &str) -> Vec<ParseError> {
+        match parse_rules("test", data) {
+            Ok(_) => Vec::new(),
+            Err(e) => e
+        }
+    }
+
+    #[test]
+    fn valid_rules() {
+        assert!(errors("#axiom A\n\nA(x):x<2->B[+A(x+1)]\n  B->BB \n").is_empty());
+    }
+
+    #[test]
+    fn arith_rules() {
+        assert!(errors("A(w):w>0->A(w-1)B(-w)\n").is_empty());
+        assert!(errors("A(x):sqrt(x)^2%3<rand(0,1)->A(max(x,2)*-1)\n").is_empty());
+        assert!(errors("A->^(90)F(2^3)\n").is_empty());
+        assert_eq!(errors("A(x)->A(foo(x))\n").len(), 1);
+    }
+
+    #[test]
+    fn module_declaration() {
+        let ctx = parse_rules("test", "#module Apex Leaf_1\nApex->Leaf_1Apex\n").unwrap();
+
+        assert_eq!(ctx.patterns[0].pattern.to_string(), "Apex");
+        assert_eq!(errors("#module 1eaf\n")[0].found, "`1eaf`");
+
+        //declarations apply to the whole file
+        let ctx = parse_rules("test", "Apex->Leaf_1Apex\n#module Apex Leaf_1\n").unwrap();
+        assert_eq!(ctx.patterns[0].replacement.symbols.len(), 2);
+
+        //a declared name is not the prefix of a longer one
+        let e = errors("#module Apex\nApexes->F\nA->BApex_2\n");
+        assert_eq!((e[0].column, e[0].found.as_str()), (1, "`Apexes`"));
+        assert_eq!((e[1].column, e[1].found.as_str()), (5, "`Apex_2`"));
+        assert!(errors("#module Apex\nApexF->Apex[+Apex]f\n").is_empty());
+    }
+
+    #[test]
+    fn render_directives() {
+        let ctx = parse_rules("test", "#angle 25.7\n#width 0.1 5\n#shrink 0.5\n").unwrap();
+
+        assert_eq!((ctx.angle, ctx.step), (Some(25.7), None));
+        assert_eq!((ctx.width, ctx.shrink), (Some((0.1, 5.0)), Some(0.5)));
+        assert_eq!(errors("#width 1\n")[0].found, "end of line");
+        assert_eq!(errors("#shrink 2\n")[0].expected, vec!["number in ]0, 1]".to_string()]);
+
+        assert_eq!(errors("#color 0 5a232\n")[0].expected, vec!["color as rrggbb".to_string()]);
+
+        let ctx = parse_rules("test", "#growth F 0\n#growth ! 1 smooth\n").unwrap();
+        assert_eq!((ctx.growth[1].param, ctx.growth[1].function), (1, GrowthFn::Smooth));
+        assert_eq!(errors("#growth F 0 fast\n")[0].found, "`fast`");
+    }
+
+    #[test]
+    fn error_position() {
+        let e = errors("#axiom A\nA->B)C\n");
+
+        assert_eq!(e.len(), 1);
+        assert_eq!((e[0].line, e[0].column), (2, 5));
+        assert_eq!(e[0].found, "`)`");
+    }
+
+    #[test]
+    fn error_expected() {
+        let e = errors("A(x->B\n");
+
+        assert_eq!(e[0].column, 4);
+        assert!(e[0].expected.contains(&"`)`".to_string()));
+    }
+
+    #[test]
+    fn function_errors() {
+        let e = errors("A(x)->A(foo(x))\n");
+        assert_eq!((e[0].column, e[0].found.as_str()), (9, "`foo`"));
+
+        let e = errors("A(x)->A(x+min(x))\n");
+        assert_eq!((e[0].column, e[0].found.as_str()), (11, "1"));
+        assert_eq!(e[0].expected, vec!["2 argument(s) for `min`".to_string()]);
+    }
+
+    #[test]
+    fn errors_collected() {
+        let e = errors("A->B)\n#niter x\nB>->C\n");
+
+        assert_eq!(e.len(), 3);
+        assert_eq!(e[1].expected, vec!["integer".to_string()]);
+        assert_eq!((e[1].column, e[2].column), (8, 3));
+    }
+
+    #[test]
+    fn warnings() {
+        let ctx = parse_rules("test", "#foo 1
+A@0.5->B
+A@0.7->C
+B@0.5->C
+B->D
+").unwrap();
+        assert_eq!(ctx.warnings, vec!["unknown directive #foo".to_string(),
+            "probabilities of rules A, A sum to 1.2, they will be normalized".to_string()]);
+    }
 }
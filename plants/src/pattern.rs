@@ -1,5 +1,4 @@
 use core::borrow::BorrowMut;
-use rand::{thread_rng, Rng};
 use std::cmp::Ordering::{Less, Equal, Greater};
 use std::cmp::Ordering;
 use std::iter::{Iterator, Rev};
@@ -10,21 +9,28 @@ use crate::symbol::Symbol;
 use crate::bool_exp::BoolExp;
 use std::collections::HashMap;
 use std::ptr::replace;
+use rand::Rng;
+
+// Values bound to the variables of a rule when it matches
+pub type Bindings = HashMap<String, f32>;
 
 #[derive(Debug)]
 pub struct Pattern {
-    pub pattern : Symbol,       // Initial character
+    pub pattern : SymbolString, // Predecessor, one or more symbols
     pub replacement : SymbolString, // Replacement string
     pub p : f32,               // Replacement probability
     pub left : Option<SymbolString>,          // Left context
     pub right : Option<SymbolString>,         // Right context
-    pub cond : Option<Box<BoolExp>> //condition
+    pub cond : Option<Box<BoolExp>>, //condition
+    pub group : usize           // Rules sharing predecessor, contexts and condition
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::borrow::BorrowMut;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
 
     fn test_rctx(s : &str, pat : &str) -> bool {
         let s = match SymbolString::from_string(s) {
@@ -54,6 +60,22 @@ mod tests {
         b
     }
 
+    #[test]
+    fn test_is_pure() {
+        let ctx = crate::parse_rules::parse_rules("test", "A(x)->A(x+1)B(x)\n").unwrap();
+        let light = ctx.to_light_ctx();
+        let s = SymbolString::from_string("A(1)A(5)").unwrap();
+        let p = &ctx.patterns[0];
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let b1 = p.test(0, &s, &light, &mut rng).unwrap();
+        let b2 = p.test(1, &s, &light, &mut rng).unwrap();
+
+        assert_eq!(p.instantiate(&b1, &light, &mut rng).to_string(), "A(2)B(1)");
+        assert_eq!(p.instantiate(&b2, &light, &mut rng).to_string(), "A(6)B(5)");
+        assert_eq!(p.replacement.to_string(), "A(1)B(0)");
+    }
+
     #[test]
     fn rctx_true() {
         let res = test_rctx("bc", "bc");
@@ -182,15 +204,15 @@ mod tests {
 }
 
 impl Pattern {
-    pub fn new<'a>(pat : Symbol, r : SymbolString, p : f32,
+    pub fn new<'a>(pat : SymbolString, r : SymbolString, p : f32,
                left : Option<SymbolString>, right : Option<SymbolString>,
                 cond : Option<Box<BoolExp>>) -> Pattern {
-        Pattern{pattern: pat, replacement: r, p, left, right, cond}
+        Pattern{pattern: pat, replacement: r, p, left, right, cond, group: 0}
     }
 
     pub fn rule_set(&mut self, rule_set: &String) {
         //modify rule_set in all symbolstrings
-        self.pattern.rule_set = rule_set.clone();
+        self.pattern.rule_set(rule_set);
         self.replacement.rule_set(rule_set);
         match &mut self.left {
             Some(l) => {l.rule_set(rule_set);},
@@ -205,7 +227,7 @@ impl Pattern {
 
     pub fn replace(&mut self, alias: &String, value: &SymbolString) {
         //replace in replacement, left and right
-        //no replacement in pattern because we do not have support for multiple symbols in a pattern
+        //no replacement in pattern, the alias value belongs to another rule set
         self.replacement.replace(alias, value);
         match &mut self.left {
             Some(l) => {l.replace(alias, value);},
@@ -233,7 +255,6 @@ impl Pattern {
             None => return (true, Vec::new())
         };
 
-        //println!("RCTX test:");
 
         let mut values = Vec::new();
         let mut lvl = 0;
@@ -247,14 +268,10 @@ impl Pattern {
                 lvl -= 1;
             }
             //ignore ignored chars and chars from different rule sets
-            if ignore.contains(&c.sym.to_string()) || c.rule_set != cur.rule_set {
-                //println!("Ignored: {}", c.to_string());
+            if c.ignored(ignore) || c.rule_set != cur.rule_set {
                 continue
             }
             else if c == cur && lvl >= 0 && lvl == pat_lvl {
-                //println!("Match: {}", c.to_string());
-                //println!("C: {}", c.to_string());
-                //println!("Cur: {}", cur.to_string());
                 let mut params = c.get_vec();
                 values.append(&mut params);
                 cur = match ctx.next() {
@@ -270,9 +287,6 @@ impl Pattern {
             }
             else {
                 if lvl >= 0 && (c == &'[' || c == &']' || lvl > pat_lvl) {
-                    //println!("Continue: lvl={}, pat_lvl={}", lvl, pat_lvl);
-                    //println!("C: {}", c.to_string());
-                    //println!("Cur: {}", cur.to_string());
                     continue;
                 }
                 else {
@@ -311,7 +325,7 @@ impl Pattern {
 
             //ignore branches that came before the current char
             //because they are not part of the left context
-            else if lvl <= min_lvl  && !(ignore.contains(&c.sym.to_string())
+            else if lvl <= min_lvl  && !(c.ignored(ignore)
                 || c.rule_set != cur.rule_set) {
                 if c == cur {
                     let mut params = c.get_vec();
@@ -333,134 +347,134 @@ impl Pattern {
         (false, Vec::new())
     }
 
-    pub fn test(&mut self, i : usize, s : &SymbolString, ctx : &LightCtx) -> bool {
+    // Test if the pattern matches symbol i of s.
+    // On success, return the values bound to the variables of the predecessor and contexts.
+    // The probability is not taken into account, see iterate::iterate.
+    // rng draws the values of the rand() calls in the condition.
+    pub fn test<R: Rng>(&self, i : usize, s : &SymbolString, ctx : &LightCtx,
+                        rng : &mut R) -> Option<Bindings> {
         let ignored = &ctx.ignored;
-        let mut rng = thread_rng();
-        if rng.gen_bool(self.p.into()) {
-            //if (self.left == ' ') && (self.right == ' ') {  // No context
-            let mut valid = s.symbols[i] == self.pattern;
-            //println!("Letter: {:?}", self.pattern);
-            let pat_values = if !valid {
-                return false;
-            } else {
-                s.symbols[i].get_vec()
-            };
-            //partition string in left and right part
-            let left_str = s.iter().take(i);
-            let right_str = s.iter().skip(i + 1);
-            //if we have a left context, check the left context
-            let (valid_tmp, lctx_values) = match &self.left {
-                Some(ctx) => Pattern::lctx(left_str.rev(),
-                                                     ctx.symbols.iter().rev().borrow_mut(),
-                                           ignored),
-                None => (true, Vec::new())
-            };
-            valid &= valid_tmp;
-            if !valid {
-                return false;
-            }
-            //if we have a right context, check the right context
-            let (valid_tmp, rctx_values) = match &self.right {
-                Some(ctx) => Pattern::rctx(right_str,
-                                                     ctx.symbols.iter().borrow_mut(),
-                                           ignored),
-                None => (true, Vec::new())
-            };
-            //println!("Valid rctx: {}", valid_tmp);
-            valid &= valid_tmp;
-            if !valid {
-                return false;
-            }
-
-            //set values in lctx, rctx, pred
-            let mut lvars = match &mut self.left {
-                Some(ctx) => {
-                    ctx.vars()
-                },
-                None => {
-                    Vec::new()
-                }
-            };
-            let mut rvars = match &mut self.right {
-                Some(ctx) => {
-                    ctx.vars()
-                },
-                None => {
-                    Vec::new()
-                }
-            };
-            self.pattern.compute_var_names();
+        //if (self.left == ' ') && (self.right == ' ') {  // No context
+        if !self.pred_matches(i, s) {
+            return None;
+        }
+        let n = self.pred_len();
+        let matched = &s.symbols[i..i + n];
+        let pat_values : Vec<f32> = matched.iter().flat_map(|c| c.get_vec()).collect();
+        //partition string in left and right part
+        let left_str = s.iter().take(i);
+        let right_str = s.iter().skip(i + n);
+        //if we have a left context, check the left context
+        let (valid, lctx_values) = match &self.left {
+            Some(ctx) => Pattern::lctx(left_str.rev(),
+                                                 ctx.symbols.iter().rev().borrow_mut(),
+                                       ignored),
+            None => (true, Vec::new())
+        };
+        if !valid {
+            return None;
+        }
+        //if we have a right context, check the right context
+        let (valid, rctx_values) = match &self.right {
+            Some(ctx) => Pattern::rctx(right_str,
+                                                 ctx.symbols.iter().borrow_mut(),
+                                       ignored),
+            None => (true, Vec::new())
+        };
+        if !valid {
+            return None;
+        }
 
-            let mut pat_vars = &self.pattern.var_names.clone();
+        //get variable names in lctx, rctx, pred
+        let lvars = match &self.left {
+            Some(ctx) => ctx.var_names(),
+            None => Vec::new()
+        };
+        let rvars = match &self.right {
+            Some(ctx) => ctx.var_names(),
+            None => Vec::new()
+        };
+        let pat_vars = self.pattern.var_names();
 
-            //bind variable names to values in a dictionary
-            let mut bindings = HashMap::new();
-            //println!("{:?}->{:?}", lvars, lctx_values);
-            //println!("{:?}->{:?}", rvars, rctx_values);
-            for (i, var) in lvars.iter().enumerate() {
-                bindings.insert(*var, lctx_values[i].clone());
-            }
-            for (i, var) in rvars.iter().enumerate() {
-                bindings.insert(*var, rctx_values[i].clone());
-            }
-            for (i, var) in pat_vars.iter().enumerate() {
-                bindings.insert(var, pat_values[i].clone());
-            }
+        //bind variable names to values in a dictionary
+        let mut bindings = Bindings::new();
+        for (i, var) in lvars.iter().enumerate() {
+            bindings.insert(var.to_string(), lctx_values[i]);
+        }
+        for (i, var) in rvars.iter().enumerate() {
+            bindings.insert(var.to_string(), rctx_values[i]);
+        }
+        for (i, var) in pat_vars.iter().enumerate() {
+            bindings.insert(var.to_string(), pat_values[i]);
+        }
 
-            // println!("Bindings: {:?}", bindings);
-            //set values in condition
-            if let Some(cond) = &self.cond {
-                let cond_vars = cond.vars();
-                let mut cond_tmp = cond.clone();
-                for v in cond_vars {
-                    if !bindings.contains_key(v) {
-                        if !ctx.define.contains_key(v) {
-                            panic!("Could not read cond variable in binding table.");
-                        } else {
-                            cond_tmp.set(v, ctx.define[v]);
-                        }
+        //set values in condition
+        if let Some(cond) = &self.cond {
+            let cond_vars = cond.vars();
+            let mut cond_tmp = cond.clone();
+            for v in cond_vars {
+                if !bindings.contains_key(v) {
+                    if !ctx.define.contains_key(v) {
+                        panic!("Could not read cond variable in binding table.");
                     } else {
-                        cond_tmp.set(v, bindings[v]);
+                        cond_tmp.set(v, ctx.define[v]);
                     }
-                }
-                if !cond_tmp.eval() {
-                    return false;
                 } else {
-                    valid = true;
-                    //println!("Valid cond,");
+                    cond_tmp.set(v, bindings[v]);
                 }
             }
-
-            //set values in replacement
-            //not good for performance but I don't know how to do
-            let mut replace_vars = self.replacement.clone();
-            let replace_vars = replace_vars.vars();
-            for v in replace_vars {
-                if !bindings.contains_key(v) && !ctx.define.contains_key(v) {
-                    panic!("Could not read replacement variable in binding table.");
-                } else if bindings.contains_key(v) {
-                    match self.replacement.set(v, bindings[v]) {
-                        Err(()) => {eprintln!("Could not set variable for replacement.");},
-                        _ => {}
-                    };
-                } else {
-                    match self.replacement.set(v, ctx.define[v]) {
-                        Err(()) => {eprintln!("Could not set variable for replacement.");},
-                        _ => {}
-                    };
-                }
+            cond_tmp.set_random(rng);
+            if !cond_tmp.eval() {
+                return None;
             }
-
-            valid
         }
-        else {
-            false
+
+        Some(bindings)
+    }
+
+    // Test the predecessor only, on consecutive symbols of the same rule set from i.
+    pub fn pred_matches(&self, i : usize, s : &SymbolString) -> bool {
+        let n = self.pred_len();
+        i + n <= s.symbols.len()
+            && self.pattern.symbols.iter().zip(&s.symbols[i..i + n])
+                .all(|(p, c)| c == p && c.rule_set == s.symbols[i].rule_set)
+    }
+
+    // Number of symbols replaced when the rule is applied.
+    pub fn pred_len(&self) -> usize {
+        self.pattern.symbols.len()
+    }
+
+    // Build the successor of a matched symbol from the bindings returned by test.
+    // rng draws the values of the rand() calls in the successor.
+    pub fn instantiate<R: Rng>(&self, bindings : &Bindings, ctx : &LightCtx,
+                               rng : &mut R) -> SymbolString {
+        let mut result = self.replacement.clone();
+        let replace_vars : Vec<String> = self.replacement.var_names().iter()
+            .map(|v| v.to_string()).collect();
+        for v in replace_vars.iter() {
+            let v = v.as_str();
+            let val = match bindings.get(v).or_else(|| ctx.define.get(v)) {
+                Some(val) => *val,
+                None => panic!("Could not read replacement variable in binding table.")
+            };
+            if result.set(v, val).is_err() {
+                eprintln!("Could not set variable for replacement.");
+            }
         }
+        result.set_random(rng);
+
+        result
     }
 
-    // Sort list from contexted to context free.
+    // Sort list from the longest predecessor to the shortest, then from contexted to
+    // context free.
     pub fn cmp_pat(&self, pat : &Pattern) -> Ordering {
-        if self.left.is_none() && self.right.is_none() {  // 2 None
+        let len_order = pat.pred_len().cmp(&self.pred_len());
+        if len_order != Equal {
+            len_order
+        }
+        else if self.left.is_none() && self.right.is_none() {  // 2 None
             if !pat.left.is_none() || !pat.right.is_none() {  // 0-1 None
                 Greater
             }
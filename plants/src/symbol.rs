@@ -4,10 +4,11 @@ use crate::ast_to_arith;
 use crate::ast::AstNode;
 use crate::lexer::{lexer, TokenType};
 use crate::symbolstring::SymbolString;
+use rand::Rng;
 
 #[derive(Clone, Debug)]
 pub struct Symbol {
-    pub sym: char,
+    pub sym: String,//one character, or a name declared with #module
     pub var_names : Vec<String>,
     pub params: Vec<Box<Arith>>,
     pub rule_set : String,//rule set name for replacing the symbol
@@ -15,11 +16,11 @@ pub struct Symbol {
 }
 
 impl Symbol {
-    pub fn new(sym: char, params: Vec<Box<Arith>>, rule_set: String) -> Symbol {
+    pub fn new(sym: String, params: Vec<Box<Arith>>, rule_set: String) -> Symbol {
         Symbol{sym, params, var_names: Vec::new(), rule_set, object: false}
     }
 
-    pub fn new_with_values(sym: char, params: Vec<f32>, rule_set: String) -> Symbol {
+    pub fn new_with_values(sym: String, params: Vec<f32>, rule_set: String) -> Symbol {
         let params = params.iter()
             .map(|x| {arith::Var::new_value(*x)}).collect();
 
@@ -34,15 +35,15 @@ impl Symbol {
                 Err("No data for ast conversion to Symbol")
             } else {
                 let sym = &exp.children[0].data;
-                let params: Vec<Box<Arith>> = exp.children[1..].iter()
+                let params = exp.children[1..].iter()
                     .filter_map(|x| {
                         if x.children.is_empty() {
                             None
                         } else {
                             Some(Arith::create_from(&x.children[0]))
                         }
-                    }).map(|x| {x.unwrap()}).collect();
-                Ok(Symbol{sym: sym.chars().nth(0).unwrap(),
+                    }).collect::<Result<Vec<Box<Arith>>, &'static str>>()?;
+                Ok(Symbol{sym: sym.clone(),
                     var_names: Vec::new(),
                     params,
                     rule_set: String::new(),
@@ -56,39 +57,37 @@ impl Symbol {
         if !self.params.is_empty() || self.rule_set != "root".to_string() {
             false//if we have a parameter or we are not from the root file we return false
         } else {
-            self.sym.to_string() == alias.to_string()//test if we have the correct symbol
+            &self.sym == alias//test if we have the correct symbol
         }
     }
 
     pub fn set_obj(&mut self, name: &String) {
-        if &self.sym.to_string() == name {
+        if &self.sym == name {
             self.object = true;
         }
     }
 
-    pub fn compute_var_names(&mut self) {
-        //var names will be computed only once
-        if self.var_names.len() > 0 {
-            return;
-        }
+    //test if the symbol is in a list of ignored symbols separated by spaces,
+    //see parse_rules::split_ignored
+    pub fn ignored(&self, ignore: &str) -> bool {
+        ignore.split_whitespace().any(|s| s == self.sym)
+    }
 
+    pub fn var_names(&self) -> Vec<&str> {
+        let mut result = Vec::new();
         for arith in &self.params {
-            let vec = arith.vars();
-            for var in vec {
-                self.var_names.push(var.to_string());
-            }
+            result.append(&mut arith.vars());
         }
-        //self.var_names.sort();
-        //self.var_names.dedup();
+        result
     }
 
     pub fn to_string(&self) -> String {
-        let mut res = self.sym.to_string();
+        let mut res = self.sym.clone();
         if self.params.len() == 0 {//only parameter-less symbols can be objects
             if self.object {
                 res = String::from("~(");
                 res.push_str(&self.rule_set);
-                res.push(self.sym);
+                res.push_str(&self.sym);
                 res.push(')');
                 res
             } else {
@@ -107,7 +106,7 @@ impl Symbol {
         }
     }
 
-    pub fn set(&mut self, var: &str, val: f32) -> Result<(), ()> {
+    pub fn set(&mut self, var: &str, val: f32) -> Result<(), &'static str> {
         let mut fail = true;
         for mut p in &mut self.params {
             match p.set(var, val) {
@@ -117,16 +116,22 @@ impl Symbol {
         }
 
         if fail {
-            Err(())
+            Err("Unknown variable.")
         } else {
             Ok(())
         }
     }
 
+    pub fn set_random<R: Rng>(&mut self, rng: &mut R) {
+        for p in &mut self.params {
+            p.set_random(rng);
+        }
+    }
+
     //set variable at index i
-    pub fn set_i(&mut self, i: usize, val: f32) -> Result<(), ()> {
+    pub fn set_i(&mut self, i: usize, val: f32) -> Result<(), &'static str> {
         if i >= self.params.len() {
-            Err(())
+            Err("Invalid parameter index.")
         } else {
             let var = String::from({
                 let vars = self.params[i].vars();
@@ -134,10 +139,10 @@ impl Symbol {
                 if vars.len() == 1 {
                     vars[0].clone()
                 } else {
-                    return Err(())
+                    return Err("Parameter is not a variable.")
                 }
             });
-            self.params[i].set(var.as_str(), val)
+            self.params[i].set(var.as_str(), val).map_err(|_| "Unknown variable.")
         }
     }
 
@@ -150,7 +155,7 @@ impl Symbol {
         }
     }
 
-    pub fn get(&self, var: &str) -> Result<f32, ()> {
+    pub fn get(&self, var: &str) -> Result<f32, &'static str> {
         for v in &self.params {
             let vars = v.vars();
             if vars.len() == 1 && vars[0] == var {
@@ -158,12 +163,12 @@ impl Symbol {
             }
         }
 
-        Err(())
+        Err("Unknown variable.")
     }
 
-    pub fn get_i(&self, i: usize) -> Result<f32, ()> {
+    pub fn get_i(&self, i: usize) -> Result<f32, &'static str> {
         if i >= self.params.len() {
-            Err(())
+            Err("Invalid parameter index.")
         } else {
             Ok(self.params[i].eval())
         }
@@ -190,7 +195,8 @@ impl PartialEq for Symbol {
 
 impl PartialEq<char> for Symbol {
     fn eq(&self, other: &char) -> bool {
-        self.sym == *other && self.params.is_empty()
+        let mut c = self.sym.chars();
+        c.next() == Some(*other) && c.next().is_none() && self.params.is_empty()
     }
 
     fn ne(&self, other: &char) -> bool {
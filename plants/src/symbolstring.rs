@@ -6,6 +6,7 @@ use crate::lexer::TokenType;
 use crate::parse_rules;
 use crate::lexer::lexer;
 use std::iter::FromIterator;
+use rand::Rng;
 
 #[derive(Clone, Debug)]
 pub struct SymbolString {
@@ -14,33 +15,26 @@ pub struct SymbolString {
 
 
 impl SymbolString {
-    pub fn from_ast(exp: &Box<AstNode>, rule_set: String) -> Result<SymbolString, &'static str> {
+    pub fn from_ast(exp: &Box<AstNode>, _rule_set: String) -> Result<SymbolString, &'static str> {
         if exp.node_type != TokenType::Lctx && exp.node_type != TokenType::Rctx
             && exp.node_type != TokenType::Replacement && exp.node_type != TokenType::Pred
         && exp.node_type != TokenType::Pat {
             Err("SymbolString creation failed: invalid node type.")
         } else {
             let symbols = exp.children.iter()
-                .map(|x| {
-                   match Symbol::from_ast(x) {
-                       Ok(a) => a,
-                       Err(e) => {
-                           println!("Symbol [{:?}] creation failed: {}", x.node_type, e);
-                            Symbol{
-                                sym: 'a',
-                                var_names: Vec::new(),
-                                params: Vec::new(),
-                                rule_set: rule_set.clone(),
-                                object: false
-                       }}
-                   }
-                }).collect();
+                .map(Symbol::from_ast)
+                .collect::<Result<Vec<Symbol>, &'static str>>()?;
             Ok(SymbolString{symbols})
         }
     }
 
     pub fn from_string(exp: &str) -> Result<SymbolString, &'static str> {
-        let tokens = lexer(exp);
+        SymbolString::from_string_with(exp, &[])
+    }
+
+    //same as from_string, with the multi-character module names declared in the grammar
+    pub fn from_string_with(exp: &str, modules: &[String]) -> Result<SymbolString, &'static str> {
+        let tokens = lexer(exp, modules);
         let ast = match parse_rules::pat(&tokens, 0) {
             (Some(a), _) => a,
             _ => {return Err("Could not parse expression to SymbolString");}
@@ -82,14 +76,10 @@ impl SymbolString {
         self.symbols = res;
     }
 
-    pub fn vars(&mut self) -> Vec<&str> {
+    pub fn var_names(&self) -> Vec<&str> {
         let mut result = Vec::new();
-        for mut sym in &mut self.symbols {
-            sym.compute_var_names();
-            //should maybe save this in a buffer
-            for v in &sym.var_names {
-                result.push(v.as_str());
-            }
+        for sym in &self.symbols {
+            result.append(&mut sym.var_names());
         }
 
         result
@@ -105,7 +95,7 @@ impl SymbolString {
         res
     }
 
-    pub fn set(&mut self, var: &str, val: f32) -> Result<(), ()> {
+    pub fn set(&mut self, var: &str, val: f32) -> Result<(), &'static str> {
         let mut fail = true;
         for mut sym in &mut self.symbols {
             if let Ok(()) = sym.set(var, val) {
@@ -114,12 +104,18 @@ impl SymbolString {
         }
 
         if fail {
-            Err(())
+            Err("Unknown variable.")
         } else {
             Ok(())
         }
     }
 
+    pub fn set_random<R: Rng>(&mut self, rng: &mut R) {
+        for sym in &mut self.symbols {
+            sym.set_random(rng);
+        }
+    }
+
     pub fn n_params(&self) -> usize {
         let mut res = 0;
         for s in &self.symbols {
@@ -164,6 +160,10 @@ impl SymbolString {
         self.symbols.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
     pub fn iter(&self) -> std::slice::Iter<'_, Symbol> {
         self.symbols.iter()
     }